@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use tokio::sync::{mpsc, oneshot};
+
+use super::packet::Packet;
+use crate::utils::now_unix;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Acked,
+    Rejected,
+    /// no ack arrived after all attempts
+    Expired,
+}
+
+/// sends aprs messages with an id and retransmits them until the addressee acks or rejects them
+/// bridges own one, forward their own writer to it and feed it every received packet
+pub struct Messenger {
+    retry: Duration,
+    max_attempts: u32,
+    own_writer: Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+    /// keyed by our station, the addressee and the message id
+    pending: Mutex<HashMap<(String, String, String), oneshot::Sender<Outcome>>>,
+    /// inbound messages already handled keyed by sender and id, with the time they were handled
+    handled: Mutex<HashMap<(String, String), u64>>,
+}
+
+/// message ids are numeric and unique per agent run, starting from a time based offset
+/// so ids of messages still retransmitted by a previous run are not reused right away
+fn next_id() -> String {
+    static NEXT: OnceLock<AtomicU32> = OnceLock::new();
+    let next = NEXT.get_or_init(|| AtomicU32::new((now_unix() % 100_000) as u32));
+    (next.fetch_add(1, Ordering::Relaxed) % 100_000).to_string()
+}
+
+impl Default for Messenger {
+    fn default() -> Self {
+        Self::new(30, 5)
+    }
+}
+
+impl Messenger {
+    pub fn new(retry_secs: u64, max_attempts: u32) -> Self {
+        Self {
+            retry: Duration::from_secs(retry_secs.max(1)),
+            max_attempts: max_attempts.max(1),
+            own_writer: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+            handled: Mutex::new(HashMap::new()),
+        }
+    }
+    pub fn set_writer(&self, w: Option<mpsc::Sender<Vec<u8>>>) {
+        *self.own_writer.lock() = w;
+    }
+    /// sends a packet once without waiting for anything, used for acks
+    pub async fn transmit(&self, packet: String) -> bool {
+        let writer = self.own_writer.lock().clone();
+        match writer {
+            Some(writer) => writer.send(packet.into_bytes()).await.is_ok(),
+            None => false,
+        }
+    }
+    /// acks an inbound message from the station it was addressed to, messages without id are not acked
+    pub async fn ack(&self, packet: &Packet) {
+        if let (Some(addressee), Some(id)) = (&packet.addressee, &packet.message_id) {
+            self.transmit(super::ack_packet(addressee, &packet.from, id))
+                .await;
+        }
+    }
+    /// tells the sender an inbound message could not be handled
+    pub async fn reject(&self, packet: &Packet) {
+        if let (Some(addressee), Some(id)) = (&packet.addressee, &packet.message_id) {
+            self.transmit(super::rej_packet(addressee, &packet.from, id))
+                .await;
+        }
+    }
+    /// stations retransmit until they see the ack, this tells whether a message was already handled
+    /// within the last hour so a retransmit only needs to be acked again
+    pub fn already_handled(&self, packet: &Packet) -> bool {
+        let Some(ref id) = packet.message_id else {
+            return false;
+        };
+        let now = now_unix();
+        let mut handled = self.handled.lock();
+        handled.retain(|_, at| now.saturating_sub(*at) < 3600);
+        handled.contains_key(&(packet.from.to_uppercase(), id.clone()))
+    }
+    pub fn mark_handled(&self, packet: &Packet) {
+        if let Some(ref id) = packet.message_id {
+            self.handled
+                .lock()
+                .insert((packet.from.to_uppercase(), id.clone()), now_unix());
+        }
+    }
+    /// resolves a pending message if the packet acks or rejects it, returns true when it did
+    pub fn handle_reply(&self, packet: &Packet) -> bool {
+        let (Some(addressee), Some(text)) = (&packet.addressee, &packet.message) else {
+            return false;
+        };
+        let (outcome, id) = if let Some(id) = text.strip_prefix("ack") {
+            (Outcome::Acked, id)
+        } else if let Some(id) = text.strip_prefix("rej") {
+            (Outcome::Rejected, id)
+        } else {
+            return false;
+        };
+        //reply-ack capable stations append `}` and their own id
+        let id = id.split('}').next().unwrap_or_default().trim();
+        let key = (
+            addressee.to_uppercase(),
+            packet.from.to_uppercase(),
+            id.to_string(),
+        );
+        match self.pending.lock().remove(&key) {
+            Some(tx) => {
+                tx.send(outcome).ok();
+                true
+            }
+            None => false,
+        }
+    }
+    /// delivers text from one of our stations to `to`, split into as many messages as needed
+    /// later parts are only sent once the previous one was acked
+    pub async fn send(&self, from: &str, to: &str, text: &str) -> Outcome {
+        for chunk in super::split_message(text) {
+            let outcome = self.send_one(from, to, &chunk).await;
+            if outcome != Outcome::Acked {
+                return outcome;
+            }
+        }
+        Outcome::Acked
+    }
+    async fn send_one(&self, from: &str, to: &str, text: &str) -> Outcome {
+        let id = next_id();
+        let key = (from.to_uppercase(), to.to_uppercase(), id.clone());
+        let (tx, mut rx) = oneshot::channel();
+        self.pending.lock().insert(key.clone(), tx);
+        let packet = super::message_packet(from, to, text, Some(&id));
+        let mut wait = self.retry;
+        for _ in 0..self.max_attempts {
+            self.transmit(packet.clone()).await;
+            if let Ok(Ok(outcome)) = tokio::time::timeout(wait, &mut rx).await {
+                return outcome;
+            }
+            wait = (wait * 2).min(Duration::from_secs(600));
+        }
+        self.pending.lock().remove(&key);
+        Outcome::Expired
+    }
+}
@@ -0,0 +1,329 @@
+//! a fake aprs-is server for exercising the agent end to end
+//! it listens on an ephemeral port, answers the login line with a logresp and lets the caller
+//! inject lines into the feed and inspect everything the agent writes back
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+/// how long helpers wait for the agent before giving up
+const WAIT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Login {
+    pub raw: String,
+    pub callsign: String,
+    pub passcode: i64,
+    pub software: String,
+    pub filter: Option<String>,
+}
+impl Login {
+    fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.split_whitespace();
+        if parts.next()? != "user" {
+            return None;
+        }
+        let callsign = parts.next()?.to_string();
+        if parts.next()? != "pass" {
+            return None;
+        }
+        let passcode = parts.next()?.parse().ok()?;
+        let rest = parts.collect::<Vec<_>>().join(" ");
+        let (vers, filter) = match rest.split_once("filter ") {
+            Some((vers, filter)) => (vers.trim(), Some(filter.trim().to_string())),
+            None => (rest.trim(), None),
+        };
+        Some(Self {
+            raw: line.to_string(),
+            callsign,
+            passcode,
+            software: vers.trim_start_matches("vers").trim().to_string(),
+            filter,
+        })
+    }
+    /// whether the passcode matches the one aprs-is would expect for the callsign
+    pub fn is_verified(&self) -> bool {
+        let expected: i64 = callpass::Callpass::from(self.callsign.as_str()).into();
+        expected == self.passcode
+    }
+}
+
+enum Cmd {
+    Line(String),
+    Disconnect,
+}
+
+pub struct MockAprsIs {
+    addr: SocketAddr,
+    logins: mpsc::UnboundedReceiver<Login>,
+    written: mpsc::UnboundedReceiver<String>,
+    cmds: mpsc::UnboundedSender<Cmd>,
+}
+impl MockAprsIs {
+    pub async fn start() -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (login_tx, logins) = mpsc::unbounded_channel();
+        let (written_tx, written) = mpsc::unbounded_channel();
+        let (cmds, mut cmd_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            //clients are served one at a time just like a single upstream connection
+            while let Ok((sock, _)) = listener.accept().await {
+                if !serve(sock, &login_tx, &written_tx, &mut cmd_rx).await {
+                    break;
+                }
+            }
+        });
+        Ok(Self {
+            addr,
+            logins,
+            written,
+            cmds,
+        })
+    }
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+    /// a config pointing the agent at this server
+    pub fn config(&self, callsign: &str) -> crate::Config {
+        crate::Config {
+            server: self.addr.ip().to_string(),
+            port: self.addr.port(),
+            callsign: callsign.to_string(),
+            ..Default::default()
+        }
+    }
+    /// waits for the next login line sent by the agent
+    pub async fn login(&mut self) -> Login {
+        tokio::time::timeout(WAIT, self.logins.recv())
+            .await
+            .expect("timed out waiting for login")
+            .expect("mock server stopped")
+    }
+    /// sends a line to the connected agent as if it came from the aprs-is feed
+    pub fn inject(&self, line: &str) {
+        self.cmds.send(Cmd::Line(line.to_string())).ok();
+    }
+    /// drops the current client so reconnect handling can be exercised
+    pub fn disconnect(&self) {
+        self.cmds.send(Cmd::Disconnect).ok();
+    }
+    /// the next line written by the agent after login or None if nothing arrived in time
+    pub async fn next_line(&mut self) -> Option<String> {
+        tokio::time::timeout(WAIT, self.written.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+    /// skips lines until one matches the predicate and panics if none arrives in time
+    pub async fn expect_line(&mut self, pred: impl Fn(&str) -> bool) -> String {
+        while let Some(line) = self.next_line().await {
+            if pred(&line) {
+                return line;
+            }
+        }
+        panic!("expected line was not written by the agent");
+    }
+    /// asserts the agent stays silent for the given duration
+    pub async fn expect_silence(&mut self, dur: Duration) {
+        if let Ok(Some(line)) = tokio::time::timeout(dur, self.written.recv()).await {
+            panic!("unexpected line written by the agent: {line}");
+        }
+    }
+}
+
+/// serves a single client, returns false once the mock itself was dropped
+async fn serve(
+    mut sock: TcpStream,
+    logins: &mpsc::UnboundedSender<Login>,
+    written: &mpsc::UnboundedSender<String>,
+    cmds: &mut mpsc::UnboundedReceiver<Cmd>,
+) -> bool {
+    let (r, mut w) = sock.split();
+    let mut lines = tokio::io::BufReader::new(r).lines();
+    if w.write_all(b"# aprsc mock\n").await.is_err() {
+        return true;
+    }
+    let login = match lines.next_line().await {
+        Ok(Some(line)) => Login::parse(&line),
+        _ => return true,
+    };
+    let Some(login) = login else {
+        return true;
+    };
+    let resp = format!(
+        "# logresp {} {}, server MOCK\n",
+        login.callsign,
+        if login.is_verified() {
+            "verified"
+        } else {
+            "unverified"
+        }
+    );
+    if w.write_all(resp.as_bytes()).await.is_err() {
+        return true;
+    }
+    if logins.send(login).is_err() {
+        return false;
+    }
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                match line {
+                    Ok(Some(line)) => {
+                        if written.send(line).is_err() {
+                            return false;
+                        }
+                    }
+                    _ => return true,
+                }
+            }
+            cmd = cmds.recv() => {
+                match cmd {
+                    Some(Cmd::Line(mut line)) => {
+                        if !line.ends_with('\n') {
+                            line.push('\n');
+                        }
+                        if w.write_all(line.as_bytes()).await.is_err() {
+                            return true;
+                        }
+                    }
+                    Some(Cmd::Disconnect) => return true,
+                    None => return false,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::{extension_server::ConStore, extensions::smtp, shutdown::Shutdown};
+
+    /// a plain smtp server accepting every mail, yields `recipient\nbody` for each one
+    async fn fake_smtp() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut sock, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let (r, mut w) = sock.split();
+                    let mut lines = tokio::io::BufReader::new(r).lines();
+                    w.write_all(b"220 mock ESMTP\r\n").await.ok();
+                    let (mut rcpt, mut data) = (String::new(), None::<String>);
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if let Some(ref mut body) = data {
+                            if line == "." {
+                                tx.send(format!("{rcpt}\n{body}")).ok();
+                                data = None;
+                                w.write_all(b"250 queued\r\n").await.ok();
+                            } else {
+                                body.push_str(&line);
+                                body.push('\n');
+                            }
+                            continue;
+                        }
+                        let cmd = line.to_uppercase();
+                        let reply: &[u8] = if cmd.starts_with("DATA") {
+                            data = Some(String::new());
+                            b"354 go ahead\r\n"
+                        } else if cmd.starts_with("QUIT") {
+                            w.write_all(b"221 bye\r\n").await.ok();
+                            return;
+                        } else {
+                            if cmd.starts_with("RCPT TO:") {
+                                rcpt = line[8..].trim_matches(['<', '>', ' ']).to_string();
+                            }
+                            b"250 ok\r\n"
+                        };
+                        w.write_all(reply).await.ok();
+                    }
+                });
+            }
+        });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn agent_end_to_end() {
+        let mut mock = MockAprsIs::start().await.unwrap();
+        let (smtp_addr, mut mails) = fake_smtp().await;
+        let outbox = std::env::temp_dir().join(format!("aprs-agent-e2e-{}", std::process::id()));
+        let mut config = mock.config("N0CALL-1");
+        config.allowed_callsigns = vec!["N0CALL*".into()];
+        config.shutdown_timeout_secs = 2;
+        config.extensions.fixed_beacon.enabled = true;
+        config.extensions.smtp = smtp::Config {
+            enabled: true,
+            smtp_server: smtp_addr.to_string(),
+            security: smtp::Security::Plain,
+            smtp_username: String::new(),
+            from_email: "agent@example.com".into(),
+            outbox_dir: outbox.clone(),
+            ..Default::default()
+        };
+        let config = crate::Config::install(config).clone();
+        config.register_extensions();
+        let shutdown = Shutdown::new();
+        let server = tokio::spawn(super::super::start_server(
+            config,
+            ConStore::default(),
+            shutdown.clone(),
+        ));
+
+        let login = mock.login().await;
+        assert_eq!(login.callsign, "N0CALL-1");
+        assert!(login.raw.starts_with("user N0CALL-1 pass "));
+        assert!(login.is_verified());
+        assert_eq!(login.software, "APRS-AGENT 0.1");
+        assert_eq!(login.filter.as_deref(), Some("b/N0CALL*"));
+        assert_eq!(mock.addr().ip().to_string(), "127.0.0.1");
+
+        //the beacon goes out as soon as the login was answered
+        let beacon = mock.expect_line(|l| l.starts_with("N0CALL-10>")).await;
+        assert!(beacon.contains(":!3800.00N/02700.00E-"), "{beacon}");
+
+        //the message is acked only after the smtp server took the email
+        mock.inject("N0CALL-5>APRS,TCPIP*,qAC,T2TEST::EMAIL    :bob@example.com hello there{42");
+        let ack = mock.expect_line(|l| l.contains("::N0CALL-5 :ack42")).await;
+        assert!(ack.starts_with("EMAIL>"), "{ack}");
+        let mail = tokio::time::timeout(Duration::from_secs(5), mails.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(mail.starts_with("bob@example.com\n"), "{mail}");
+        assert!(mail.contains("hello there"), "{mail}");
+
+        //a retransmit of the handled message is acked again without another email
+        mock.inject("N0CALL-5>APRS,TCPIP*,qAC,T2TEST::EMAIL    :bob@example.com hello there{42");
+        mock.expect_line(|l| l.contains("::N0CALL-5 :ack42")).await;
+        assert!(mails.try_recv().is_err());
+
+        //after a reconnect the agent logs in again and beacons right away
+        mock.disconnect();
+        mock.login().await;
+        mock.expect_line(|l| l.starts_with("N0CALL-10>")).await;
+        mock.expect_silence(Duration::from_millis(500)).await;
+
+        shutdown.trigger();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("agent did not shut down")
+            .unwrap();
+        std::fs::remove_dir_all(outbox).ok();
+    }
+}
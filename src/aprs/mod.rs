@@ -1,65 +1,212 @@
 use std::time::Duration;
 
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt},
+    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
-    time::sleep,
+    sync::mpsc::Receiver,
+    time::{sleep, Instant},
 };
 
-use crate::{extension_server::ConStore, extensions};
+use crate::{extension_server::ConStore, extensions, shutdown::Shutdown};
+pub mod messaging;
+#[cfg(test)]
+mod mock;
+pub mod packet;
+pub mod position;
+pub mod stations;
+pub mod stats;
 
-pub async fn start_server(config: crate::Config, tcp_ext_store: ConStore) {
+/// destination (tocall) used for packets the agent originates
+pub const TOCALL: &str = "AP4GNT";
+
+pub async fn start_server(config: crate::Config, tcp_ext_store: ConStore, shutdown: Shutdown) {
+    stats::start();
+    extensions::ExtensionRegistry::start();
     loop {
-        let mut con = TcpStream::connect(format!("{}:{}", config.server, config.port))
-            .await
-            .expect("failed to connect to aprs server");
-        let passcode: i64 = callpass::Callpass::from(config.callsign.as_str()).into();
-        con.write_all(
-            format!(
-                "user {} pass {} vers APRS-AGENT 0.1 filter b/{}\n",
-                config.callsign,
-                passcode,
-                config.allowed_callsigns.join("/")
-            )
-            .as_bytes(),
-        )
-        .await
-        .expect("failed to write to aprs server");
+        let Some(mut con) = connect(&config, &shutdown).await else {
+            extensions::ExtensionRegistry::shutdown(deadline(&config)).await;
+            tcp_ext_store.close();
+            eprintln!("shutdown complete");
+            return;
+        };
         let (r, mut w) = con.split();
         let reader = tokio::io::BufReader::new(r);
         let mut lines = reader.lines();
         let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(1);
         extensions::ExtensionRegistry::set_own_writers(tx);
-        loop {
+        let reason = loop {
             tokio::select! {
+                biased;
+                _ = shutdown.wait() => {
+                    drain(&config, &mut rx, &mut w).await;
+                    tcp_ext_store.close();
+                    w.shutdown().await.ok();
+                    extensions::ExtensionRegistry::disconnected("shutdown");
+                    eprintln!("disconnected from server, shutdown complete");
+                    return;
+                }
                 line = lines.next_line() => {
-                    let line = line.unwrap_or_default().unwrap_or_default();
-                    if line.is_empty(){
-                        break;
+                    let line = match line {
+                        Ok(Some(line)) if !line.is_empty() => line,
+                        Ok(_) => break "connection closed by server".to_string(),
+                        Err(e) => break format!("failed to read from aprs server: {e}"),
+                    };
+                    if let Some((verified, server)) = parse_logresp(&line) {
+                        eprintln!("logged in to {server} verified: {verified}");
+                        extensions::ExtensionRegistry::connected(server, verified);
                     }
-                if extensions::ExtensionRegistry::broadcast(&line, &mut w).await.is_err(){
-                    break;
+                    stations::record(&line);
+                    stats::record_received(&line);
+                if let Err(e) = extensions::ExtensionRegistry::broadcast(&line, &mut w).await {
+                    break format!("failed to write to aprs server: {e}");
                 }
                 tcp_ext_store.broadcast(line);
                 }
                 line = rx.recv() => {
-                    if let Some(mut line) = line {
-                        if line.is_empty(){
-                            continue;
-                        }
-                        if line.last() != Some(&b'\n'){
-                            line.push(b'\n');
-                        }
-                        eprintln!("--> {}", String::from_utf8_lossy(&line));
-                        if let Err(e) = w.write_all(&line).await {
-                            eprintln!("failed to write to aprs server: {}", e);
-                            break
+                    if let Some(line) = line {
+                        if let Err(e) = write_own_line(&mut w, line).await {
+                            break format!("failed to write to aprs server: {e}");
                         };
                     }
                 }
             }
+        };
+        extensions::ExtensionRegistry::disconnected(&reason);
+        eprintln!("disconnected from server ({reason}), reconnecting in 1s");
+        tokio::select! {
+            _ = sleep(Duration::from_secs(1)) => {}
+            _ = shutdown.wait() => {
+                extensions::ExtensionRegistry::shutdown(deadline(&config)).await;
+                tcp_ext_store.close();
+                eprintln!("shutdown complete");
+                return;
+            }
+        }
+    }
+}
+
+/// connects and logs in, retrying with backoff until it succeeds or shutdown is triggered
+async fn connect(config: &crate::Config, shutdown: &Shutdown) -> Option<TcpStream> {
+    let addr = format!("{}:{}", config.server, config.port);
+    let passcode: i64 = callpass::Callpass::from(config.callsign.as_str()).into();
+    let login = format!(
+        "user {} pass {} vers APRS-AGENT 0.1 filter b/{}\n",
+        config.callsign,
+        passcode,
+        config.allowed_callsigns.join("/")
+    );
+    let mut retry = Duration::from_secs(1);
+    loop {
+        let attempt = async {
+            let mut con = TcpStream::connect(&addr).await?;
+            con.write_all(login.as_bytes()).await?;
+            Ok::<_, std::io::Error>(con)
+        };
+        tokio::select! {
+            con = attempt => match con {
+                Ok(con) => return Some(con),
+                Err(e) => eprintln!("failed to connect to {addr} ({e}), retrying in {}s", retry.as_secs()),
+            },
+            _ = shutdown.wait() => return None,
+        }
+        tokio::select! {
+            _ = sleep(retry) => {}
+            _ = shutdown.wait() => return None,
+        }
+        retry = (retry * 2).min(Duration::from_secs(60));
+    }
+}
+
+/// parses `# logresp CALL verified, server NAME` into the verification state and server name
+fn parse_logresp(line: &str) -> Option<(bool, &str)> {
+    let mut parts = line.strip_prefix("# logresp ")?.split_whitespace();
+    let _callsign = parts.next()?;
+    let verified = parts.next()?.trim_end_matches(',') == "verified";
+    let server = match (parts.next(), parts.next()) {
+        (Some("server"), Some(server)) => server,
+        _ => "unknown",
+    };
+    Some((verified, server))
+}
+
+fn deadline(config: &crate::Config) -> Instant {
+    Instant::now() + Duration::from_secs(config.shutdown_timeout_secs)
+}
+
+/// runs the extension shutdown hooks while still forwarding whatever they write through their own writers
+async fn drain(config: &crate::Config, rx: &mut Receiver<Vec<u8>>, mut w: impl AsyncWrite + Unpin) {
+    let hooks = extensions::ExtensionRegistry::shutdown(deadline(config));
+    tokio::pin!(hooks);
+    loop {
+        tokio::select! {
+            _ = &mut hooks => break,
+            line = rx.recv() => {
+                let Some(line) = line else {
+                    (&mut hooks).await;
+                    return;
+                };
+                if write_own_line(&mut w, line).await.is_err() {
+                    return;
+                }
+            }
         }
-        eprintln!("disconnected from server, reconnecting in 1s");
-        sleep(Duration::from_secs(1)).await;
     }
+    while let Ok(line) = rx.try_recv() {
+        if write_own_line(&mut w, line).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn write_own_line(mut w: impl AsyncWrite + Unpin, mut line: Vec<u8>) -> std::io::Result<()> {
+    if line.is_empty() {
+        return Ok(());
+    }
+    if line.last() != Some(&b'\n') {
+        line.push(b'\n');
+    }
+    eprintln!("--> {}", String::from_utf8_lossy(&line));
+    if let Err(e) = w.write_all(&line).await {
+        eprintln!("failed to write to aprs server: {}", e);
+        return Err(e);
+    }
+    stats::record_transmitted();
+    Ok(())
+}
+
+/// latitude and longitude in decimal degrees for packets that carry a position
+pub fn position_of(data: &aprs_parser::AprsData) -> Option<(f64, f64)> {
+    match data {
+        aprs_parser::AprsData::Position(p) => Some((*p.latitude, *p.longitude)),
+        aprs_parser::AprsData::MicE(m) => Some((*m.latitude, *m.longitude)),
+        _ => None,
+    }
+}
+
+/// formats an aprs message, the addressee is padded to 9 characters as the spec requires
+pub fn message_packet(from: &str, to: &str, text: &str, id: Option<&str>) -> String {
+    let id = id.map(|id| format!("{{{id}")).unwrap_or_default();
+    format!(
+        "{from}>{TOCALL},TCPIP*::{to: <9}:{text}{id}\n",
+        from = from.to_uppercase(),
+        to = to.to_uppercase()
+    )
+}
+
+/// acknowledges a message, sent from the station the message was addressed to
+pub fn ack_packet(from: &str, to: &str, msg_id: &str) -> String {
+    message_packet(from, to, &format!("ack{msg_id}"), None)
+}
+
+/// rejects a message, sent from the station the message was addressed to
+pub fn rej_packet(from: &str, to: &str, msg_id: &str) -> String {
+    message_packet(from, to, &format!("rej{msg_id}"), None)
+}
+
+/// longest text a single aprs message can carry
+pub const MAX_MESSAGE_LEN: usize = 67;
+
+/// splits text into chunks that fit a single aprs message, breaking at whitespace when possible
+pub fn split_message(text: &str) -> Vec<String> {
+    crate::utils::split_text(text, MAX_MESSAGE_LEN)
 }
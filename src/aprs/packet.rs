@@ -0,0 +1,107 @@
+use aprs_parser::AprsData;
+use serde::Serialize;
+
+use crate::utils::now_unix;
+
+/// decoded view of an aprs-is line handed to extensions that work with structured packets
+/// header fields come from the raw line so they are filled even if the payload cannot be decoded
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct Packet {
+    pub raw: String,
+    pub from: String,
+    pub to: String,
+    pub path: Vec<String>,
+    pub data_type: Option<char>,
+    pub payload: String,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub symbol_table: Option<char>,
+    pub symbol: Option<char>,
+    pub comment: Option<String>,
+    pub addressee: Option<String>,
+    pub message: Option<String>,
+    pub message_id: Option<String>,
+    pub received_at: u64,
+}
+
+impl Packet {
+    /// returns None for server comments and lines without a valid header
+    pub fn parse(line: &str) -> Option<Self> {
+        if line.starts_with('#') {
+            return None;
+        }
+        let (header, payload) = line.split_once(':')?;
+        let (from, rest) = header.split_once('>')?;
+        let mut path = rest.split(',').map(ToString::to_string);
+        let to = path.next()?;
+        let mut packet = Self {
+            raw: line.to_string(),
+            from: from.to_string(),
+            to,
+            path: path.collect(),
+            data_type: payload.chars().next(),
+            payload: payload.to_string(),
+            received_at: now_unix(),
+            ..Default::default()
+        };
+        if let Ok(decoded) = aprs_parser::AprsPacket::decode_textual(line.as_bytes()) {
+            if let Some((lat, lon)) = super::position_of(&decoded.data) {
+                packet.latitude = Some(lat);
+                packet.longitude = Some(lon);
+            }
+            match decoded.data {
+                AprsData::Position(p) => {
+                    packet.symbol_table = Some(p.symbol_table);
+                    packet.symbol = Some(p.symbol_code);
+                    packet.comment = Some(String::from_utf8_lossy(&p.comment).to_string());
+                }
+                AprsData::Message(m) => {
+                    packet.addressee =
+                        Some(String::from_utf8_lossy(&m.addressee).trim().to_string());
+                    packet.message = Some(String::from_utf8_lossy(&m.text).to_string());
+                    packet.message_id = m.id.map(|id| String::from_utf8_lossy(&id).to_string());
+                }
+                _ => {}
+            }
+        }
+        if packet.data_type == Some('>') {
+            packet.comment = Some(payload[1..].to_string());
+        }
+        Some(packet)
+    }
+    /// human readable packet type derived from the data type identifier
+    pub fn kind(&self) -> &'static str {
+        match self.data_type {
+            Some('!' | '=' | '/' | '@') => "position",
+            Some('`' | '\'') => "mic_e",
+            Some(':')
+                if self
+                    .message
+                    .as_deref()
+                    .is_some_and(|m| m.starts_with("ack")) =>
+            {
+                "ack"
+            }
+            Some(':') => "message",
+            Some('>') => "status",
+            Some(';') => "object",
+            Some(')') => "item",
+            Some('T') => "telemetry",
+            Some('_') => "weather",
+            Some('<') => "capabilities",
+            _ => "other",
+        }
+    }
+    /// value of a packet field for templates, `{type}` resolves to the packet kind
+    pub fn var(&self, name: &str) -> Option<String> {
+        if name == "type" {
+            return Some(self.kind().to_string());
+        }
+        let value = serde_json::to_value(self).ok()?;
+        Some(match value.get(name)? {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::String(s) => s.clone(),
+            v => v.to_string(),
+        })
+    }
+}
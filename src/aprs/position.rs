@@ -0,0 +1,182 @@
+//! encoding of positions and their data extensions for packets we originate
+
+/// `DDMM.mmN`, rounded to a hundredth of a minute
+pub fn encode_lat(lat: f64) -> String {
+    let (deg, min) = degrees_minutes(lat);
+    let hemisphere = if lat < 0.0 { 'S' } else { 'N' };
+    format!("{deg:02}{min:05.2}{hemisphere}")
+}
+
+/// `DDDMM.mmE`, rounded to a hundredth of a minute
+pub fn encode_lon(lon: f64) -> String {
+    let (deg, min) = degrees_minutes(lon);
+    let hemisphere = if lon < 0.0 { 'W' } else { 'E' };
+    format!("{deg:03}{min:05.2}{hemisphere}")
+}
+
+fn degrees_minutes(v: f64) -> (u32, f64) {
+    //round on whole hundredths so 59.999 minutes carries into the degrees
+    let hundredths = (v.abs() * 6000.0).round() as u32;
+    (hundredths / 6000, (hundredths % 6000) as f64 / 100.0)
+}
+
+/// parses `DDMM.mmN` or `DDDMM.mmE`, ambiguity spaces count as zeros
+pub fn parse_coordinate(s: &str, deg_digits: usize, hemispheres: [char; 2]) -> Option<f64> {
+    let hemisphere = s.chars().last()?;
+    let digits = s[..s.len() - hemisphere.len_utf8()].replace(' ', "0");
+    if !hemispheres.contains(&hemisphere)
+        || digits.len() != deg_digits + 5
+        || digits.as_bytes()[deg_digits + 2] != b'.'
+    {
+        return None;
+    }
+    let deg = digits[..deg_digits].parse::<u32>().ok()?;
+    let min = digits[deg_digits..].parse::<f64>().ok()?;
+    if min >= 60.0 {
+        return None;
+    }
+    let v = deg as f64 + min / 60.0;
+    Some(if hemisphere == hemispheres[1] { -v } else { v })
+}
+
+pub fn valid_lat_lon(lat: f64, lon: f64) -> bool {
+    (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)
+}
+
+/// what the two `cs` bytes of a compressed position carry
+#[derive(Debug, Clone, Copy)]
+pub enum CompressedExt {
+    None,
+    CourseSpeed { course: u16, speed_knots: u16 },
+    RangeMiles(u16),
+}
+
+/// base91 compressed position `TYYYYXXXXScsT` where the first T is the symbol table
+pub fn encode_compressed(
+    lat: f64,
+    lon: f64,
+    table: char,
+    symbol: char,
+    ext: CompressedExt,
+) -> String {
+    let y = (380926.0 * (90.0 - lat)) as u32;
+    let x = (190463.0 * (180.0 + lon)) as u32;
+    let mut out = String::with_capacity(13);
+    //numeric overlays are sent as a-j in the compressed format
+    out.push(match table {
+        '0'..='9' => char::from(b'a' + table as u8 - b'0'),
+        t => t,
+    });
+    out.push_str(&base91(y, 4));
+    out.push_str(&base91(x, 4));
+    out.push(symbol);
+    let base = |v: u32| char::from(33 + v.min(90) as u8);
+    match ext {
+        CompressedExt::None => out.push_str("  "),
+        CompressedExt::CourseSpeed {
+            course,
+            speed_knots,
+        } => {
+            out.push(base(u32::from(course % 360) / 4));
+            out.push(base(
+                ((speed_knots as f64 + 1.0).ln() / 1.08f64.ln()).round() as u32,
+            ));
+        }
+        CompressedExt::RangeMiles(range) => {
+            out.push('{');
+            out.push(base(
+                ((range as f64 / 2.0).max(1.0).ln() / 1.08f64.ln()).round() as u32,
+            ));
+        }
+    }
+    //current fix, software origin
+    out.push(base(0b10_0010));
+    out
+}
+
+fn base91(mut v: u32, len: usize) -> String {
+    let mut out = vec![b'!'; len];
+    for c in out.iter_mut().rev() {
+        *c = 33 + (v % 91) as u8;
+        v /= 91;
+    }
+    String::from_utf8(out).unwrap_or_default()
+}
+
+/// `PHGphgd`, each value is rounded to the nearest code the extension can express
+pub fn encode_phg(power_watts: u32, height_feet: u32, gain_db: u8, directivity: u16) -> String {
+    let p = (power_watts as f64).sqrt().round().min(9.0) as u8;
+    let h = (height_feet.max(10) as f64 / 10.0).log2().round().min(9.0) as u8;
+    let d = directivity / 45 % 9;
+    format!("PHG{p}{h}{}{d}", gain_db.min(9))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncompressed() {
+        assert_eq!(encode_lat(49.058333), "4903.50N");
+        assert_eq!(encode_lon(-72.029167), "07201.75W");
+        assert_eq!(encode_lat(-0.5), "0030.00S");
+        assert_eq!(encode_lon(180.0), "18000.00E");
+        //59.9999 minutes round up into the next degree
+        assert_eq!(encode_lat(10.999999), "1100.00N");
+    }
+
+    #[test]
+    fn coordinates() {
+        let close = |a: Option<f64>, b: f64| a.is_some_and(|a| (a - b).abs() < 1e-6);
+        assert!(close(
+            parse_coordinate("4903.50N", 2, ['N', 'S']),
+            49.058333
+        ));
+        assert!(close(
+            parse_coordinate("07201.75W", 3, ['E', 'W']),
+            -72.029167
+        ));
+        assert!(close(parse_coordinate("4903.  N", 2, ['N', 'S']), 49.05));
+        assert_eq!(parse_coordinate("4960.00N", 2, ['N', 'S']), None);
+        assert_eq!(parse_coordinate("4903.50E", 2, ['N', 'S']), None);
+        assert_eq!(parse_coordinate("49035.0N", 2, ['N', 'S']), None);
+        assert_eq!(parse_coordinate("4903.5N", 2, ['N', 'S']), None);
+        assert_eq!(parse_coordinate("", 2, ['N', 'S']), None);
+        assert!(valid_lat_lon(-90.0, 180.0));
+        assert!(!valid_lat_lon(90.1, 0.0));
+    }
+
+    #[test]
+    fn compressed() {
+        //examples from the aprs 1.01 spec
+        assert_eq!(
+            encode_compressed(49.5, -72.75, '/', '>', CompressedExt::None),
+            "/5L!!<*e7>  C"
+        );
+        assert_eq!(
+            encode_compressed(
+                49.5,
+                -72.75,
+                '/',
+                '>',
+                CompressedExt::CourseSpeed {
+                    course: 88,
+                    speed_knots: 36
+                }
+            ),
+            "/5L!!<*e7>7PC"
+        );
+        assert_eq!(
+            encode_compressed(49.5, -72.75, '/', '>', CompressedExt::RangeMiles(20)),
+            "/5L!!<*e7>{?C"
+        );
+        assert!(encode_compressed(49.5, -72.75, '3', '#', CompressedExt::None).starts_with('d'));
+    }
+
+    #[test]
+    fn phg() {
+        assert_eq!(encode_phg(25, 20, 3, 90), "PHG5132");
+        assert_eq!(encode_phg(0, 0, 0, 0), "PHG0000");
+        assert_eq!(encode_phg(1000, 10_000, 20, 360), "PHG9998");
+    }
+}
@@ -0,0 +1,81 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use parking_lot::Mutex;
+
+use crate::utils::now_unix;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Position {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// unix time the position was heard
+    pub heard_at: u64,
+}
+
+/// positions older than this are forgotten once the table grows large
+const MAX_AGE_SECS: u64 = 24 * 3600;
+const MAX_STATIONS: usize = 10_000;
+
+/// last line of a station whose data type can carry a position and when it was heard
+struct Heard {
+    line: String,
+    at: u64,
+}
+
+fn positions() -> &'static Mutex<HashMap<String, Heard>> {
+    static POSITIONS: OnceLock<Mutex<HashMap<String, Heard>>> = OnceLock::new();
+    POSITIONS.get_or_init(Default::default)
+}
+
+/// remembers the line of the sending station if it can carry a position
+///
+/// the line is only decoded when its position is looked up, most are never asked for
+pub fn record(line: &str) {
+    let Some((header, payload)) = line.split_once(':') else {
+        return;
+    };
+    if line.starts_with('#') || !payload.starts_with(['!', '=', '/', '@', '`', '\'']) {
+        return;
+    }
+    let Some((from, _)) = header.split_once('>') else {
+        return;
+    };
+    let now = now_unix();
+    let mut positions = positions().lock();
+    if positions.len() >= MAX_STATIONS {
+        positions.retain(|_, h| now.saturating_sub(h.at) < MAX_AGE_SECS);
+    }
+    positions.insert(
+        from.to_uppercase(),
+        Heard {
+            line: line.to_string(),
+            at: now,
+        },
+    );
+}
+
+/// last position heard from a station, the ssid has to match
+pub fn last_position(station: &str) -> Option<Position> {
+    let (line, heard_at) = {
+        let positions = positions().lock();
+        let heard = positions.get(&station.to_uppercase())?;
+        (heard.line.clone(), heard.at)
+    };
+    let packet = aprs_parser::AprsPacket::decode_textual(line.as_bytes()).ok()?;
+    let (latitude, longitude) = super::position_of(&packet.data)?;
+    Some(Position {
+        latitude,
+        longitude,
+        heard_at,
+    })
+}
+
+/// number of stations whose position reports were heard within the last `secs` seconds
+pub fn heard_within(secs: u64) -> usize {
+    let now = now_unix();
+    positions()
+        .lock()
+        .values()
+        .filter(|h| now.saturating_sub(h.at) < secs)
+        .count()
+}
@@ -0,0 +1,68 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    OnceLock,
+};
+
+use crate::utils::now_unix;
+
+/// counters of the upstream connection since the agent started
+#[derive(Default)]
+struct Counters {
+    received: AtomicU64,
+    messages: AtomicU64,
+    transmitted: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    /// packets heard from aprs-is, server comments are not counted
+    pub received: u64,
+    /// messages among the received packets
+    pub messages: u64,
+    /// lines the extensions sent through their own writers
+    pub transmitted: u64,
+    pub uptime_secs: u64,
+}
+
+fn counters() -> &'static Counters {
+    static COUNTERS: OnceLock<Counters> = OnceLock::new();
+    COUNTERS.get_or_init(Default::default)
+}
+
+fn started_at() -> u64 {
+    static STARTED_AT: OnceLock<u64> = OnceLock::new();
+    *STARTED_AT.get_or_init(now_unix)
+}
+
+/// starts the uptime clock, later calls keep the first start time
+pub fn start() {
+    started_at();
+}
+
+pub fn record_received(line: &str) {
+    let Some((_, payload)) = line.split_once(':') else {
+        return;
+    };
+    if line.starts_with('#') {
+        return;
+    }
+    let c = counters();
+    c.received.fetch_add(1, Ordering::Relaxed);
+    if payload.starts_with(':') {
+        c.messages.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn record_transmitted() {
+    counters().transmitted.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn snapshot() -> Snapshot {
+    let c = counters();
+    Snapshot {
+        received: c.received.load(Ordering::Relaxed),
+        messages: c.messages.load(Ordering::Relaxed),
+        transmitted: c.transmitted.load(Ordering::Relaxed),
+        uptime_secs: now_unix().saturating_sub(started_at()),
+    }
+}
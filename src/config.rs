@@ -1,7 +1,13 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    extensions::{fixed_beacon, logger, smtp, twitter, ExtensionRegistry},
+    extensions::{
+        email_gateway, fixed_beacon, logger, mastodon, matrix, mqtt, objects, process,
+        queue::QueueSettings, script, smtp, status, telegram, twitter, wasm, webhook,
+        ExtensionRegistry,
+    },
     flags::{flags, Flags},
 };
 #[macro_export]
@@ -27,7 +33,11 @@ pub struct Config {
     ))]
     pub allowed_callsigns: Vec<String>,
     pub print_config_on_startup: bool,
+    /// how long extensions get to flush their work once SIGTERM is received
+    #[educe(Default = 10)]
+    pub shutdown_timeout_secs: u64,
     pub extension_server: ExtensionServerSettings,
+    pub extension_runtime: ExtensionRuntimeSettings,
     pub extensions: Extensions,
 }
 #[derive(Debug, Serialize, Deserialize, Educe, Clone)]
@@ -40,21 +50,57 @@ pub struct ExtensionServerSettings {
     #[educe(Default = 65080)]
     pub port: u16,
 }
+#[derive(Debug, Serialize, Deserialize, Educe, Clone)]
+#[educe(Default)]
+#[serde(default)]
+pub struct ExtensionRuntimeSettings {
+    /// upper bound for a single handle call, slower calls are abandoned and count as a failure
+    #[educe(Default = 30)]
+    pub timeout_secs: u64,
+    /// consecutive failures after which an extension is disabled temporarily, 0 never disables
+    #[educe(Default = 5)]
+    pub failure_threshold: u32,
+    #[educe(Default = 300)]
+    pub cooldown_secs: u64,
+    /// queue and worker pool used by spawnable extensions like the logger
+    pub queue: QueueSettings,
+    /// per extension overrides of the queue settings keyed by extension name
+    pub queues: HashMap<String, QueueSettings>,
+}
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 #[serde(default)]
 pub struct Extensions {
     pub twitter: twitter::Config,
+    pub mastodon: mastodon::Config,
     pub logger: logger::Config,
     pub fixed_beacon: fixed_beacon::Config,
     pub smtp: smtp::Config,
+    pub email_gateway: email_gateway::Config,
+    pub mqtt: mqtt::Config,
+    pub telegram: telegram::Config,
+    pub matrix: matrix::Config,
+    pub objects: objects::Config,
+    pub status: status::Config,
+    /// external programs, each one is configured as its own `[[extensions.process]]` table
+    pub process: Vec<process::Config>,
+    /// rhai scripts, each one is configured as its own `[[extensions.script]]` table
+    pub script: Vec<script::Config>,
+    /// wasi plugins, each one is configured as its own `[[extensions.wasm]]` table
+    pub wasm: Vec<wasm::Config>,
+    /// http endpoints, each one is configured as its own `[[extensions.webhook]]` table
+    pub webhook: Vec<webhook::Config>,
 }
 static mut CONFIG: Option<Config> = None;
 impl Config {
     pub fn get() -> &'static Config {
-        unsafe { CONFIG.as_ref().expect("config not initialized") }
+        unsafe {
+            (*std::ptr::addr_of!(CONFIG))
+                .as_ref()
+                .expect("config not initialized")
+        }
     }
     pub fn parse(flags: &Flags) -> Config {
-        if unsafe { CONFIG.is_some() } {
+        if unsafe { (*std::ptr::addr_of!(CONFIG)).is_some() } {
             panic!("config already initialized");
         }
         let cpath = &flags.config;
@@ -72,12 +118,47 @@ impl Config {
         }
         config
     }
+    /// installs an already built config as the global one, used to drive the agent from tests
+    #[cfg(test)]
+    pub fn install(config: Config) -> &'static Config {
+        unsafe {
+            CONFIG = Some(config);
+        }
+        Self::get()
+    }
     pub fn register_extensions(&self) {
         switch! {
-            self.extensions.twitter.enabled => ExtensionRegistry::register(twitter::Twitter::new(&self.extensions.twitter));
-            self.extensions.logger.enabled => ExtensionRegistry::register(logger::Logger);
-            self.extensions.smtp.enabled => ExtensionRegistry::register(smtp::SmtpEmailer::new(&self.extensions.smtp));
-            self.extensions.fixed_beacon.enabled => ExtensionRegistry::register(fixed_beacon::FixedBeacon::new(&self.extensions.fixed_beacon))
+            self.extensions.twitter.enabled => ExtensionRegistry::register_filtered(twitter::Twitter::new(&self.extensions.twitter), &self.extensions.twitter.filter);
+            self.extensions.mastodon.enabled => ExtensionRegistry::register_filtered(mastodon::Mastodon::new(&self.extensions.mastodon), &self.extensions.mastodon.filter);
+            self.extensions.logger.enabled => ExtensionRegistry::register_filtered(logger::Logger, &self.extensions.logger.filter);
+            self.extensions.smtp.enabled => ExtensionRegistry::register_filtered(smtp::SmtpEmailer::new(&self.extensions.smtp), &self.extensions.smtp.filter);
+            self.extensions.mqtt.enabled => ExtensionRegistry::register_filtered(mqtt::Mqtt::new(&self.extensions.mqtt), &self.extensions.mqtt.filter);
+            self.extensions.telegram.enabled => ExtensionRegistry::register_filtered(telegram::Telegram::new(&self.extensions.telegram), &self.extensions.telegram.filter);
+            self.extensions.matrix.enabled => ExtensionRegistry::register_filtered(matrix::Matrix::new(&self.extensions.matrix), &self.extensions.matrix.filter);
+            self.extensions.email_gateway.enabled => ExtensionRegistry::register_filtered(email_gateway::EmailGateway::new(&self.extensions.email_gateway), &self.extensions.email_gateway.filter);
+            self.extensions.fixed_beacon.enabled => ExtensionRegistry::register(fixed_beacon::FixedBeacon::new(&self.extensions.fixed_beacon));
+            self.extensions.objects.enabled => ExtensionRegistry::register(objects::Objects::new(&self.extensions.objects));
+            self.extensions.status.enabled => ExtensionRegistry::register(status::Status::new(&self.extensions.status))
+        }
+        for cfg in self.extensions.process.iter().filter(|p| p.enabled) {
+            ExtensionRegistry::register_filtered(process::ProcessExtension::new(cfg), &cfg.filter);
+        }
+        for cfg in self.extensions.script.iter().filter(|s| s.enabled) {
+            ExtensionRegistry::register_filtered(script::ScriptExtension::new(cfg), &cfg.filter);
+        }
+        for cfg in self.extensions.webhook.iter().filter(|w| w.enabled) {
+            ExtensionRegistry::register_filtered(webhook::Webhook::new(cfg), &cfg.filter);
+        }
+        #[cfg(feature = "wasm")]
+        for cfg in self.extensions.wasm.iter().filter(|w| w.enabled) {
+            ExtensionRegistry::register_filtered(wasm::WasmExtension::new(cfg), &cfg.filter);
+        }
+        #[cfg(not(feature = "wasm"))]
+        if let Some(cfg) = self.extensions.wasm.iter().find(|w| w.enabled) {
+            panic!(
+                "wasm extension {} is configured but the agent was built without the wasm feature",
+                cfg.name
+            );
         }
     }
     pub fn sync_file(&self) {
@@ -9,7 +9,7 @@ use tokio::{
     sync::mpsc::UnboundedSender,
 };
 
-use crate::{config::Config, utils::now_unix};
+use crate::{config::Config, extensions::ExtensionRegistry, utils::now_unix};
 
 #[derive(Debug, Default, Clone)]
 pub struct ConStore {
@@ -29,6 +29,13 @@ impl ConStore {
             sock.send(msg.clone()).ok();
         }
     }
+    /// drops every client sender so handlers flush what is queued and then hang up
+    pub fn close(&self) {
+        let clients = std::mem::take(&mut *self.store.write());
+        if !clients.is_empty() {
+            eprintln!("closing {} extension server clients", clients.len());
+        }
+    }
 }
 
 pub fn start(cfg: Config) -> ConStore {
@@ -82,8 +89,11 @@ async fn handler(mut sock: TcpStream, addr: SocketAddr, store: ConStore) {
         tokio::select! {
             line = lines.next_line() => {
                 let cmd = get_cmd!(line, addr);
-                //so far only ping is handled so this if is fine
-                if cmd == ClientCmd::Ping && w.write_all(format!("{}\n", ServerCmd::Pong).as_bytes()).await.is_err() {
+                let resp = match cmd {
+                    ClientCmd::Ping => ServerCmd::Pong,
+                    ClientCmd::Stats => ServerCmd::Stats(ExtensionRegistry::dropped_lines()),
+                };
+                if w.write_all(format!("{}\n", resp).as_bytes()).await.is_err() {
                     break;
                 }
             },
@@ -109,6 +119,7 @@ async fn handler(mut sock: TcpStream, addr: SocketAddr, store: ConStore) {
 #[derive(Debug, PartialEq)]
 pub enum ClientCmd {
     Ping,
+    Stats,
 }
 
 impl FromStr for ClientCmd {
@@ -116,6 +127,7 @@ impl FromStr for ClientCmd {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "ping" => Ok(ClientCmd::Ping),
+            "stats" => Ok(ClientCmd::Stats),
             _ => Err(crate::error::ExtServerErrors::InvalidCmd(s.to_string()).into()),
         }
     }
@@ -124,12 +136,21 @@ impl FromStr for ClientCmd {
 enum ServerCmd {
     Pong,
     Data(String),
+    /// lines dropped per spawnable extension queue
+    Stats(Vec<(&'static str, u64)>),
 }
 impl Display for ServerCmd {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ServerCmd::Pong => write!(f, "pong {}", now_unix()),
             ServerCmd::Data(data) => write!(f, "data {}", data),
+            ServerCmd::Stats(dropped) => {
+                write!(f, "stats")?;
+                for (name, count) in dropped {
+                    write!(f, " {name}.dropped={count}")?;
+                }
+                Ok(())
+            }
         }
     }
 }
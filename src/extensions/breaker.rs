@@ -0,0 +1,112 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use super::Extension;
+
+/// per extension circuit breaker, after `failure_threshold` consecutive failures (timeouts or panics)
+/// the extension is skipped for `cooldown_secs`, afterwards a single failure trips it again
+#[derive(Default)]
+pub struct CircuitBreaker(Mutex<State>);
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn allows(&self) -> bool {
+        let mut state = self.0.lock();
+        match state.open_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                state.open_until = None;
+                true
+            }
+            None => true,
+        }
+    }
+    pub fn record_success(&self) {
+        self.0.lock().failures = 0;
+    }
+    pub fn record_failure(&self, ext: &(dyn Extension + Send + Sync)) {
+        let cfg = &crate::Config::get().extension_runtime;
+        if self.fail(
+            cfg.failure_threshold,
+            Duration::from_secs(cfg.cooldown_secs),
+        ) {
+            ext.warn(&format!(
+                "disabled for {}s after {} consecutive failures",
+                cfg.cooldown_secs, cfg.failure_threshold
+            ));
+        }
+    }
+    /// counts a failure, returns whether it tripped the breaker, a threshold of 0 never trips it
+    fn fail(&self, threshold: u32, cooldown: Duration) -> bool {
+        if threshold == 0 {
+            return false;
+        }
+        let mut state = self.0.lock();
+        state.failures += 1;
+        if state.failures < threshold {
+            return false;
+        }
+        //stay one failure short of the threshold so a failing probe trips it right away
+        state.failures = threshold - 1;
+        state.open_until = Some(Instant::now() + cooldown);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(300);
+
+    #[test]
+    fn trips_at_threshold() {
+        let breaker = CircuitBreaker::default();
+        assert!(!breaker.fail(3, COOLDOWN));
+        assert!(!breaker.fail(3, COOLDOWN));
+        assert!(breaker.allows());
+        assert!(breaker.fail(3, COOLDOWN));
+        //calls are rejected for the whole cooldown
+        assert!(!breaker.allows());
+        assert!(!breaker.allows());
+    }
+
+    #[test]
+    fn success_resets_the_count() {
+        let breaker = CircuitBreaker::default();
+        breaker.fail(2, COOLDOWN);
+        breaker.record_success();
+        assert!(!breaker.fail(2, COOLDOWN));
+        assert!(breaker.allows());
+    }
+
+    #[test]
+    fn half_open_after_cooldown() {
+        let breaker = CircuitBreaker::default();
+        breaker.fail(2, Duration::ZERO);
+        assert!(breaker.fail(2, Duration::ZERO));
+        //the cooldown is over, a probe is let through and a single failure trips it again
+        assert!(breaker.allows());
+        assert!(breaker.fail(2, Duration::ZERO));
+        //a successful probe closes it, it takes the whole threshold to trip again
+        assert!(breaker.allows());
+        breaker.record_success();
+        assert!(!breaker.fail(2, Duration::ZERO));
+        assert!(breaker.fail(2, Duration::ZERO));
+    }
+
+    #[test]
+    fn zero_threshold_never_trips() {
+        let breaker = CircuitBreaker::default();
+        for _ in 0..100 {
+            assert!(!breaker.fail(0, COOLDOWN));
+        }
+        assert!(breaker.allows());
+    }
+}
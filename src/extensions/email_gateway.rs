@@ -0,0 +1,528 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use base64::Engine;
+use educe::Educe;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    sync::{mpsc, Semaphore},
+};
+
+use super::{filter::Filter, Extension};
+use crate::{
+    aprs::{
+        messaging::{Messenger, Outcome},
+        packet::Packet,
+    },
+    utils::glob_match,
+};
+
+/// accepts mail over smtp on a local port and delivers it to aprs stations
+/// the station is taken from the recipient address (`n0call-7@...`) or the first word of the subject
+/// replies the station sends to `alias` go back by email through the `smtp` extension settings
+/// the listener does no authentication, put it behind a real mail server or keep it on localhost
+#[derive(Debug, Serialize, Deserialize, Clone, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct Config {
+    pub enabled: bool,
+    #[educe(Default = "127.0.0.1:2525")]
+    pub listen: String,
+    /// stations see mails coming from this name and reply to it
+    #[educe(Default = "MAILGW")]
+    pub alias: String,
+    /// sender addresses, `*` and `?` wildcards are allowed
+    pub allowed_senders: Vec<String>,
+    /// only this many characters of the mail are delivered
+    #[educe(Default = 200)]
+    pub max_chars: usize,
+    /// bigger mails are refused and the connection is closed
+    #[educe(Default = 1_048_576)]
+    pub max_mail_bytes: usize,
+    /// longer lines close the connection, smtp allows 1000 including the line ending
+    #[educe(Default = 1000)]
+    pub max_line_bytes: usize,
+    /// clients silent for this long are disconnected
+    #[educe(Default = 60)]
+    pub idle_timeout_secs: u64,
+    /// further clients are turned away with a 421 until a session ends
+    #[educe(Default = 16)]
+    pub max_connections: usize,
+    /// first retransmit of an unacked aprs message, doubled after every attempt
+    #[educe(Default = 30)]
+    pub retry_secs: u64,
+    #[educe(Default = 5)]
+    pub max_attempts: u32,
+    pub filter: Filter,
+}
+
+/// the interesting parts of a received mail
+struct Mail {
+    from: String,
+    subject: String,
+    text: String,
+}
+
+#[derive(Clone)]
+pub struct EmailGateway(Arc<Inner>);
+struct Inner {
+    cfg: Config,
+    messenger: Messenger,
+    /// email address that last wrote to a station, where its replies are sent
+    correspondents: Mutex<HashMap<String, String>>,
+}
+
+impl EmailGateway {
+    pub fn new(cfg: &Config) -> Self {
+        if cfg.allowed_senders.is_empty() {
+            panic!("email gateway requires at least one allowed sender");
+        }
+        if cfg.max_line_bytes == 0 || cfg.idle_timeout_secs == 0 || cfg.max_connections == 0 {
+            panic!("email gateway needs a positive line limit, idle timeout and connection limit");
+        }
+        Self(Arc::new(Inner {
+            cfg: cfg.clone(),
+            messenger: Messenger::new(cfg.retry_secs, cfg.max_attempts),
+            correspondents: Mutex::new(HashMap::new()),
+        }))
+    }
+    async fn listen(&self) {
+        let listener = match TcpListener::bind(&self.0.cfg.listen).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                self.error(&format!("failed to listen on {}: {e}", self.0.cfg.listen));
+                return;
+            }
+        };
+        self.log(&format!("accepting mail on {}", self.0.cfg.listen));
+        let sessions = Arc::new(Semaphore::new(self.0.cfg.max_connections));
+        loop {
+            match listener.accept().await {
+                Ok((mut stream, addr)) => {
+                    let Ok(permit) = sessions.clone().try_acquire_owned() else {
+                        self.warn(&format!("too many connections, turning away {addr}"));
+                        stream.write_all(b"421 too busy, try later\r\n").await.ok();
+                        continue;
+                    };
+                    let ext = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = ext.session(stream).await {
+                            ext.warn(&format!("smtp session failed: {e}"));
+                        }
+                        drop(permit);
+                    });
+                }
+                Err(e) => self.error(&format!("failed to accept: {e}")),
+            }
+        }
+    }
+    /// next line without its line ending, none at eof
+    /// fails on lines over `max_line_bytes` and clients idle longer than `idle_timeout_secs`
+    async fn read_line(&self, r: &mut BufReader<OwnedReadHalf>) -> std::io::Result<Option<String>> {
+        use std::io::{Error, ErrorKind};
+        let cfg = &self.0.cfg;
+        let mut line = vec![];
+        let mut limited = r.take(cfg.max_line_bytes as u64 + 2);
+        let read = limited.read_until(b'\n', &mut line);
+        let n = tokio::time::timeout(Duration::from_secs(cfg.idle_timeout_secs), read)
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "client was idle too long"))??;
+        if n == 0 {
+            return Ok(None);
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+        if line.len() > cfg.max_line_bytes {
+            return Err(Error::new(ErrorKind::InvalidData, "line too long"));
+        }
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+    /// the subset of smtp mail clients and relays need to hand over a mail
+    async fn session(&self, stream: TcpStream) -> std::io::Result<()> {
+        let (r, mut w) = stream.into_split();
+        let mut r = BufReader::new(r);
+        w.write_all(b"220 aprs-agent ESMTP\r\n").await?;
+        let (mut from, mut rcpts) = (None::<String>, vec![]);
+        while let Some(line) = self.read_line(&mut r).await? {
+            let (verb, arg) = line.split_once(' ').unwrap_or((&line, ""));
+            let reply: &[u8] = match verb.to_ascii_uppercase().as_str() {
+                "HELO" | "EHLO" => b"250 aprs-agent\r\n",
+                "MAIL" => {
+                    from = Some(address(arg));
+                    rcpts.clear();
+                    b"250 ok\r\n"
+                }
+                "RCPT" if from.is_none() => b"503 need MAIL first\r\n",
+                "RCPT" if rcpts.len() >= 100 => b"452 too many recipients\r\n",
+                "RCPT" => {
+                    rcpts.push(address(arg));
+                    b"250 ok\r\n"
+                }
+                "DATA" if from.is_none() || rcpts.is_empty() => b"503 need RCPT first\r\n",
+                "DATA" => {
+                    w.write_all(b"354 end with <CRLF>.<CRLF>\r\n").await?;
+                    let mut data = String::new();
+                    while let Some(line) = self.read_line(&mut r).await? {
+                        if line == "." {
+                            break;
+                        }
+                        if data.len() + line.len() > self.0.cfg.max_mail_bytes {
+                            //the rest is not read, the client has to go
+                            w.write_all(b"552 message too big\r\n").await?;
+                            return Ok(());
+                        }
+                        data.push_str(line.strip_prefix('.').unwrap_or(&line));
+                        data.push('\n');
+                    }
+                    let envelope_from = from.take().unwrap_or_default();
+                    let rcpts = std::mem::take(&mut rcpts);
+                    if self.accept(&envelope_from, &rcpts, &data) {
+                        b"250 queued\r\n"
+                    } else {
+                        b"550 not accepted\r\n"
+                    }
+                }
+                "RSET" => {
+                    from = None;
+                    rcpts.clear();
+                    b"250 ok\r\n"
+                }
+                "NOOP" => b"250 ok\r\n",
+                "QUIT" => {
+                    w.write_all(b"221 bye\r\n").await?;
+                    return Ok(());
+                }
+                _ => b"502 not implemented\r\n",
+            };
+            w.write_all(reply).await?;
+        }
+        Ok(())
+    }
+    /// checks a received mail and starts delivering it, false rejects it
+    fn accept(&self, envelope_from: &str, rcpts: &[String], data: &str) -> bool {
+        let mail = parse_mail(data);
+        let from = if mail.from.is_empty() {
+            envelope_from.to_string()
+        } else {
+            mail.from
+        };
+        let allowed = |addr: &str| {
+            self.0
+                .cfg
+                .allowed_senders
+                .iter()
+                .any(|p| glob_match(p, addr))
+        };
+        if !allowed(&from) || !allowed(envelope_from) {
+            self.warn(&format!("rejecting mail from {from}"));
+            return false;
+        }
+        let mut subject = mail.subject.split_whitespace();
+        let mut stations = rcpts
+            .iter()
+            .filter_map(|r| r.split('@').next())
+            .filter(|local| is_callsign(local))
+            .map(str::to_uppercase)
+            .collect::<Vec<_>>();
+        let mut text = mail.text;
+        if stations.is_empty() {
+            match subject.next() {
+                Some(word) if is_callsign(word) => stations.push(word.to_uppercase()),
+                _ => {
+                    self.warn(&format!("mail from {from} does not name a station"));
+                    return false;
+                }
+            }
+        }
+        if text.is_empty() {
+            text = subject.collect::<Vec<_>>().join(" ");
+        }
+        let text = text.chars().take(self.0.cfg.max_chars).collect::<String>();
+        if text.is_empty() {
+            return false;
+        }
+        for station in stations {
+            self.0
+                .correspondents
+                .lock()
+                .insert(station.clone(), from.clone());
+            let ext = self.clone();
+            let (from, text) = (from.clone(), text.clone());
+            tokio::spawn(async move {
+                let outcome = ext
+                    .0
+                    .messenger
+                    .send(&ext.0.cfg.alias, &station, &text)
+                    .await;
+                ext.log(&format!("mail from {from} to {station}: {outcome:?}"));
+                if outcome != Outcome::Acked {
+                    let body = format!(
+                        "Your message to {station} could not be delivered ({outcome:?}).\n\n{text}"
+                    );
+                    ext.reply(
+                        &from,
+                        &format!("Undelivered APRS message to {station}"),
+                        body,
+                    )
+                    .await;
+                }
+            });
+        }
+        true
+    }
+    /// sends an email through the smtp extension settings, returns false if it failed
+    async fn reply(&self, to: &str, subject: &str, body: String) -> bool {
+        let cfg = crate::Config::get().extensions.smtp.clone();
+        let message = (|| {
+            lettre::Message::builder()
+                .from(
+                    cfg.from_email
+                        .parse()
+                        .map_err(|e| format!("invalid from email: {e}"))?,
+                )
+                .to(to.parse().map_err(|e| format!("invalid email: {e}"))?)
+                .date_now()
+                .subject(subject)
+                .body(body)
+                .map_err(|e| e.to_string())
+        })();
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                self.error(&format!("failed to build email to {to}: {e}"));
+                return false;
+            }
+        };
+        let sent = match super::smtp::mailer(&cfg) {
+            Ok(mailer) => {
+                use lettre::AsyncTransport;
+                mailer.send(message).await.map_err(|e| e.to_string())
+            }
+            Err(e) => Err(e),
+        };
+        match sent {
+            Ok(_) => true,
+            Err(e) => {
+                self.error(&format!("failed to send email to {to}: {e}"));
+                false
+            }
+        }
+    }
+}
+
+/// a plausible callsign with optional ssid, has to contain a digit so plain names do not match
+fn is_callsign(s: &str) -> bool {
+    (3..=9).contains(&s.len())
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && s.chars().any(|c| c.is_ascii_digit())
+}
+
+/// address out of `FROM:<a@b>`, `TO:<a@b>` or `Name <a@b>`
+fn address(s: &str) -> String {
+    let s = s.split_once(':').map(|(_, a)| a).unwrap_or(s);
+    let s = match (s.find('<'), s.find('>')) {
+        (Some(start), Some(end)) if start < end => &s[start + 1..end],
+        _ => s,
+    };
+    s.trim().to_lowercase()
+}
+
+/// headers and body of a mime entity, folded header lines are joined
+fn split_entity(data: &str) -> (HashMap<String, String>, &str) {
+    let (head, body) = data
+        .split_once("\n\n")
+        .or_else(|| data.split_once("\r\n\r\n"))
+        .unwrap_or((data, ""));
+    let mut headers = HashMap::<String, String>::new();
+    let mut last = None::<String>;
+    for line in head.lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some(value) = last.as_ref().and_then(|k| headers.get_mut(k)) {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((k, v)) = line.split_once(':') {
+            let k = k.trim().to_lowercase();
+            headers
+                .entry(k.clone())
+                .or_insert_with(|| v.trim().to_string());
+            last = Some(k);
+        }
+    }
+    (headers, body)
+}
+
+/// value of a parameter like `boundary` in a content type header
+fn param(header: &str, name: &str) -> Option<String> {
+    header.split(';').skip(1).find_map(|p| {
+        let (k, v) = p.split_once('=')?;
+        k.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| v.trim().trim_matches('"').to_string())
+    })
+}
+
+/// the plain text of an entity, multipart mails are searched for their first text/plain part
+fn plain_text(headers: &HashMap<String, String>, body: &str) -> Option<String> {
+    let content_type = headers
+        .get("content-type")
+        .map(String::as_str)
+        .unwrap_or("text/plain");
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    if mime.starts_with("multipart/") {
+        let boundary = format!("--{}", param(content_type, "boundary")?);
+        return body
+            .split(boundary.as_str())
+            .skip(1)
+            .take_while(|part| !part.starts_with("--"))
+            .find_map(|part| {
+                let (headers, body) = split_entity(part.trim_start_matches(['\r', '\n']));
+                plain_text(&headers, body)
+            });
+    }
+    if mime != "text/plain" {
+        return None;
+    }
+    let encoding = headers
+        .get("content-transfer-encoding")
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    let bytes = match encoding.as_str() {
+        "base64" => base64::engine::general_purpose::STANDARD
+            .decode(body.split_whitespace().collect::<String>())
+            .ok()?,
+        "quoted-printable" => decode_quoted_printable(body),
+        _ => body.as_bytes().to_vec(),
+    };
+    Some(String::from_utf8_lossy(&bytes).to_string())
+}
+
+fn decode_quoted_printable(body: &str) -> Vec<u8> {
+    let mut out = vec![];
+    for line in body.lines() {
+        let (line, soft_break) = match line.trim_end().strip_suffix('=') {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        let bytes = line.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .and_then(|h| std::str::from_utf8(h).ok())
+                .and_then(|h| u8::from_str_radix(h, 16).ok());
+            match (bytes[i], hex) {
+                (b'=', Some(b)) => {
+                    out.push(b);
+                    i += 3;
+                }
+                (b, _) => {
+                    out.push(b);
+                    i += 1;
+                }
+            }
+        }
+        if !soft_break {
+            out.push(b'\n');
+        }
+    }
+    out
+}
+
+fn parse_mail(data: &str) -> Mail {
+    let (headers, body) = split_entity(data);
+    let text = plain_text(&headers, body).unwrap_or_default();
+    //quoted replies and the signature are of no use on a small screen
+    let text = text
+        .lines()
+        .take_while(|l| l.trim_end() != "--")
+        .filter(|l| !l.starts_with('>'))
+        .flat_map(str::split_whitespace)
+        .collect::<Vec<_>>()
+        .join(" ");
+    Mail {
+        from: headers.get("from").map(|f| address(f)).unwrap_or_default(),
+        subject: headers.get("subject").cloned().unwrap_or_default(),
+        text,
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for EmailGateway {
+    fn name(&self) -> &'static str {
+        "email_gateway"
+    }
+    async fn handle(&self, line: &str) -> Option<Vec<u8>> {
+        let packet = Packet::parse(line)?;
+        if self.0.messenger.handle_reply(&packet) || packet.kind() != "message" {
+            return None;
+        }
+        if !packet
+            .addressee
+            .as_ref()?
+            .eq_ignore_ascii_case(&self.0.cfg.alias)
+        {
+            return None;
+        }
+        let to = self
+            .0
+            .correspondents
+            .lock()
+            .get(&packet.from.to_uppercase())
+            .cloned()?;
+        if self.0.messenger.already_handled(&packet) {
+            self.0.messenger.ack(&packet).await;
+            return None;
+        }
+        let ext = self.clone();
+        tokio::spawn(async move {
+            let subject = format!("APRS message from {}", packet.from);
+            let body = packet.message.clone().unwrap_or_default();
+            if ext.reply(&to, &subject, body).await {
+                ext.0.messenger.mark_handled(&packet);
+                ext.0.messenger.ack(&packet).await;
+            }
+        });
+        None
+    }
+    fn set_own_writer(&self, w: mpsc::Sender<Vec<u8>>) {
+        self.0.messenger.set_writer(Some(w));
+    }
+    fn on_start(&self) {
+        let ext = self.clone();
+        tokio::spawn(async move { ext.listen().await });
+    }
+    fn on_disconnected(&self, _reason: &str) {
+        self.0.messenger.set_writer(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_printable() {
+        let decode = |s: &str| String::from_utf8(decode_quoted_printable(s)).unwrap();
+        assert_eq!(decode("plain text"), "plain text\n");
+        assert_eq!(decode("caf=C3=A9 =3D ok"), "café = ok\n");
+        assert_eq!(decode("soft =\r\nbreak"), "soft break\n");
+        assert_eq!(decode("two\r\nlines"), "two\nlines\n");
+        //malformed escapes are kept as they are
+        assert_eq!(decode("50=ZZ off ="), "50=ZZ off ");
+        assert_eq!(decode("end=4"), "end=4\n");
+    }
+}
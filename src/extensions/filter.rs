@@ -0,0 +1,259 @@
+use std::cell::OnceCell;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{distance_km, glob_match};
+
+/// inbound filter that can be put under any extension's config as a `filter` table
+/// every non empty criterion has to match for a line to reach the extension, an empty filter matches everything
+/// server comments (lines starting with `#`) only pass an empty filter
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Filter {
+    /// source callsign globs e.g. `TA*` or `TA3PKS-?`
+    pub from: Vec<String>,
+    /// message addressee globs, only message packets can match
+    pub addressee: Vec<String>,
+    /// data type identifiers e.g. `:` for messages or `!` for positions
+    pub data_types: Vec<char>,
+    /// globs of which at least one has to match a path element e.g. `WIDE2*` or `qAR`
+    pub path: Vec<String>,
+    /// only packets with a position inside the circle pass
+    pub distance: Option<Distance>,
+    /// regex matched against the packet payload, everything after the first `:`
+    pub payload_regex: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Distance {
+    pub lat: f64,
+    pub lon: f64,
+    pub radius_km: f64,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.from.is_empty()
+            && self.addressee.is_empty()
+            && self.data_types.is_empty()
+            && self.path.is_empty()
+            && self.distance.is_none()
+            && self.payload_regex.is_none()
+    }
+    /// compiles the regex once so the filter can be evaluated for every line, panics on an invalid regex
+    pub fn compile(&self, ext_name: &str) -> Compiled {
+        let payload_regex = self.payload_regex.as_ref().map(|r| {
+            Regex::new(r)
+                .unwrap_or_else(|e| panic!("{ext_name}: invalid filter payload_regex: {e}"))
+        });
+        Compiled {
+            filter: self.clone(),
+            payload_regex,
+        }
+    }
+}
+
+pub struct Compiled {
+    filter: Filter,
+    payload_regex: Option<Regex>,
+}
+impl Compiled {
+    pub fn matches(&self, line: &Line) -> bool {
+        let f = &self.filter;
+        if f.is_empty() {
+            return true;
+        }
+        let Some(source) = line.source else {
+            return false;
+        };
+        if !f.from.is_empty() && !f.from.iter().any(|p| glob_match(p, source)) {
+            return false;
+        }
+        if !f.data_types.is_empty() && !line.data_type().is_some_and(|t| f.data_types.contains(&t))
+        {
+            return false;
+        }
+        if !f.addressee.is_empty()
+            && !line
+                .addressee()
+                .is_some_and(|a| f.addressee.iter().any(|p| glob_match(p, a)))
+        {
+            return false;
+        }
+        if !f.path.is_empty()
+            && !line
+                .path
+                .iter()
+                .any(|el| f.path.iter().any(|p| glob_match(p, el)))
+        {
+            return false;
+        }
+        if let Some(ref re) = self.payload_regex {
+            if !re.is_match(line.payload) {
+                return false;
+            }
+        }
+        if let Some(ref d) = f.distance {
+            match line.position() {
+                Some((lat, lon)) if distance_km(d.lat, d.lon, lat, lon) <= d.radius_km => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// the parts of a raw aprs-is line filters look at
+/// the packet is only decoded if a distance filter asks for the position
+pub struct Line<'a> {
+    raw: &'a str,
+    source: Option<&'a str>,
+    path: Vec<&'a str>,
+    payload: &'a str,
+    position: OnceCell<Option<(f64, f64)>>,
+}
+impl<'a> Line<'a> {
+    pub fn new(raw: &'a str) -> Self {
+        let (header, payload) = raw.split_once(':').unwrap_or(("", ""));
+        let (source, path) = match header.split_once('>') {
+            Some((source, rest)) if !raw.starts_with('#') => {
+                //the first element is the destination, not part of the path
+                (Some(source), rest.split(',').skip(1).collect())
+            }
+            _ => (None, vec![]),
+        };
+        Self {
+            raw,
+            source,
+            path,
+            payload,
+            position: OnceCell::new(),
+        }
+    }
+    fn data_type(&self) -> Option<char> {
+        self.payload.chars().next()
+    }
+    fn addressee(&self) -> Option<&str> {
+        let msg = self.payload.strip_prefix(':')?;
+        let (addressee, _) = msg.split_once(':')?;
+        Some(addressee.trim())
+    }
+    fn position(&self) -> Option<(f64, f64)> {
+        *self.position.get_or_init(|| {
+            let packet = aprs_parser::AprsPacket::decode_textual(self.raw.as_bytes()).ok()?;
+            crate::aprs::position_of(&packet.data)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POSITION: &str = "TA3PKS-7>APRS,WIDE2-1,qAR,TA1ABC:!4100.00N/02900.00E>moving";
+    const MESSAGE: &str = "N0CALL>APRS,TCPIP*::WXBOT    :weather?{1";
+
+    fn matches(filter: Filter, line: &str) -> bool {
+        filter.compile("test").matches(&Line::new(line))
+    }
+
+    #[test]
+    fn empty_matches_everything() {
+        assert!(matches(Filter::default(), POSITION));
+        assert!(matches(Filter::default(), "# aprsc 2.1.14"));
+    }
+
+    #[test]
+    fn callsign_glob() {
+        let from = |p: &str| Filter {
+            from: vec![p.into()],
+            ..Default::default()
+        };
+        assert!(matches(from("TA*"), POSITION));
+        assert!(matches(from("TA3PKS-?"), POSITION));
+        assert!(!matches(from("TA3PKS"), POSITION));
+        assert!(!matches(from("TA*"), "# TA3PKS>APRS:>comment"));
+        let addressee = Filter {
+            addressee: vec!["WX*".into()],
+            ..Default::default()
+        };
+        assert!(matches(addressee.clone(), MESSAGE));
+        assert!(!matches(addressee, POSITION));
+    }
+
+    #[test]
+    fn data_type() {
+        let filter = Filter {
+            data_types: vec!['!', '='],
+            ..Default::default()
+        };
+        assert!(matches(filter.clone(), POSITION));
+        assert!(!matches(filter, MESSAGE));
+    }
+
+    #[test]
+    fn path() {
+        let path = |p: &str| Filter {
+            path: vec![p.into()],
+            ..Default::default()
+        };
+        assert!(matches(path("WIDE2*"), POSITION));
+        assert!(matches(path("qAR"), POSITION));
+        //the destination is not part of the path
+        assert!(!matches(path("APRS"), POSITION));
+    }
+
+    #[test]
+    fn distance() {
+        let within = |radius_km| Filter {
+            distance: Some(Distance {
+                lat: 41.0,
+                lon: 28.0,
+                radius_km,
+            }),
+            ..Default::default()
+        };
+        assert!(matches(within(100.0), POSITION));
+        assert!(!matches(within(50.0), POSITION));
+        assert!(!matches(within(100.0), MESSAGE));
+    }
+
+    #[test]
+    fn payload_regex() {
+        let filter = Filter {
+            payload_regex: Some("^:WXBOT +:weather".into()),
+            ..Default::default()
+        };
+        assert!(matches(filter.clone(), MESSAGE));
+        assert!(!matches(filter, POSITION));
+    }
+
+    #[test]
+    fn criteria_combine() {
+        let filter = Filter {
+            from: vec!["TA*".into()],
+            data_types: vec!['!'],
+            path: vec!["qA?".into()],
+            payload_regex: Some("moving$".into()),
+            ..Default::default()
+        };
+        assert!(matches(filter.clone(), POSITION));
+        assert!(!matches(
+            filter.clone(),
+            &POSITION.replace("moving", "parked")
+        ));
+        assert!(!matches(filter, &POSITION.replace("TA3PKS", "N0CALL")));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid filter payload_regex")]
+    fn invalid_regex() {
+        Filter {
+            payload_regex: Some("(".into()),
+            ..Default::default()
+        }
+        .compile("test");
+    }
+}
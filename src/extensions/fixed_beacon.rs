@@ -3,28 +3,146 @@ use std::{error::Error, sync::Arc, time::Duration};
 use educe::Educe;
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
 
 use super::Extension;
+use crate::aprs::position::{self, CompressedExt};
 
 #[derive(Debug, Serialize, Deserialize, Educe, Clone)]
 #[educe(Default)]
 #[serde(default)]
 pub struct Config {
     pub enabled: bool,
+    /// the single beacon of older configs, only sent when `beacons` is empty
+    #[serde(flatten)]
+    pub beacon: Beacon,
+    pub beacons: Vec<Beacon>,
+}
+impl Config {
+    pub fn beacons(&self) -> Vec<Beacon> {
+        if self.beacons.is_empty() {
+            vec![self.beacon.clone()]
+        } else {
+            self.beacons.clone()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Educe, Clone)]
+#[educe(Default)]
+#[serde(default)]
+pub struct Beacon {
     #[educe(Default = "N0CALL-10")]
     pub ssid: String,
     #[educe(Default = "3800.00N")]
     pub lat: String,
     #[educe(Default = "02700.00E")]
     pub lon: String,
+    /// decimal degrees, used instead of `lat` and `lon` when both are set
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// base91 compressed position instead of `DDMM.mmN`
+    pub compressed: bool,
     #[educe(Default = '/')]
     pub symbol_table: char,
     #[educe(Default = '-')]
     pub symbol: char,
     #[educe(Default = "https://github.com/ta3pks/aprs-agent")]
     pub comment: String,
+    /// at most one of `course_speed`, `phg` and `range_miles` can be set
+    pub course_speed: Option<CourseSpeed>,
+    pub phg: Option<Phg>,
+    pub range_miles: Option<u16>,
+    pub altitude_feet: Option<i32>,
+    #[educe(Default = "AP4GNT")]
+    pub destination: String,
+    /// comma separated digipeater path after the destination
+    #[educe(Default = "TCPIP*,qAC,APRSAGENT")]
+    pub path: String,
     #[educe(Default = 15)]
     pub beacon_interval_mins: u64,
+    /// seconds after every (re)connect before the first transmission, spreads the beacons out
+    pub slot_offset_secs: u64,
+}
+
+impl Beacon {
+    fn position(&self) -> Option<(f64, f64)> {
+        match (self.latitude, self.longitude) {
+            (Some(lat), Some(lon)) => Some((lat, lon)),
+            _ => Some((
+                position::parse_coordinate(&self.lat, 2, ['N', 'S'])?,
+                position::parse_coordinate(&self.lon, 3, ['E', 'W'])?,
+            )),
+        }
+    }
+    /// position, data extension and comment following the `!`
+    fn info(&self) -> String {
+        let (lat, lon) = self.position().unwrap_or_default();
+        let mut info = if self.compressed {
+            let ext = match (self.course_speed, self.range_miles) {
+                (Some(cs), _) => CompressedExt::CourseSpeed {
+                    course: cs.course,
+                    speed_knots: cs.speed_knots,
+                },
+                (_, Some(range)) => CompressedExt::RangeMiles(range),
+                _ => CompressedExt::None,
+            };
+            position::encode_compressed(lat, lon, self.symbol_table, self.symbol, ext)
+        } else {
+            //configured strings are sent as is to keep their position ambiguity
+            let (lat, lon) = match (self.latitude, self.longitude) {
+                (Some(_), Some(_)) => (position::encode_lat(lat), position::encode_lon(lon)),
+                _ => (self.lat.clone(), self.lon.clone()),
+            };
+            let mut info = format!("{lat}{}{lon}{}", self.symbol_table, self.symbol);
+            if let Some(cs) = self.course_speed {
+                info.push_str(&format!("{:03}/{:03}", cs.course, cs.speed_knots));
+            } else if let Some(phg) = self.phg {
+                info.push_str(&position::encode_phg(
+                    phg.power_watts,
+                    phg.height_feet,
+                    phg.gain_db,
+                    phg.directivity,
+                ));
+            } else if let Some(range) = self.range_miles {
+                info.push_str(&format!("RNG{range:04}"));
+            }
+            info
+        };
+        if let Some(alt) = self.altitude_feet {
+            info.push_str(&format!("/A={alt:06}"));
+        }
+        info.push_str(&self.comment);
+        info
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct CourseSpeed {
+    /// degrees, 360 is north and 0 unknown
+    pub course: u16,
+    pub speed_knots: u16,
+}
+
+/// power, antenna height above average terrain, gain and directivity of the station
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct Phg {
+    pub power_watts: u32,
+    pub height_feet: u32,
+    pub gain_db: u8,
+    /// degrees the antenna favours in steps of 45, 0 for omni
+    pub directivity: u16,
+}
+impl Default for Phg {
+    fn default() -> Self {
+        Self {
+            power_watts: 10,
+            height_feet: 20,
+            gain_db: 3,
+            directivity: 0,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -34,41 +152,42 @@ impl FixedBeacon {
         if !cfg.enabled {
             panic!("fixed beacon is not enabled but tried to be created");
         }
-        if cfg.ssid.len() > 9 {
-            panic!("ssid cannot be longer than 9 characters");
-        }
-        if cfg.lat.len() > 8 {
-            panic!("lat cannot be longer than 8 characters");
-        }
-        if cfg.lon.len() > 9 {
-            panic!("lon cannot be longer than 9 characters");
-        }
-        if !&['N', 'S'].contains(&cfg.lat.chars().last().unwrap_or(0x00 as char)) {
-            panic!("lat must end with N or S");
-        }
-        if !&['E', 'W'].contains(&cfg.lon.chars().last().unwrap_or(0x00 as char)) {
-            panic!("lon must end with E or W");
+        let beacons = cfg.beacons();
+        for b in &beacons {
+            validate(b);
         }
         let inner = FixedBeaconInner {
             own_writer: None,
-            is_worker_running: false,
+            online: watch::channel(false).0,
+            beacons,
         };
         Self(Arc::new(Mutex::new(inner)))
     }
-    fn start(&self) {
-        let cfg = &crate::Config::get().extensions.fixed_beacon;
-        let ext = self.clone();
-        tokio::spawn(async move {
-            ext.0.lock().is_worker_running = true;
-            loop {
-                if let Err(e) = ext.send().await {
-                    ext.error(&format!("failed to send beacon: {}", e));
+    async fn run(&self, beacon: Beacon) {
+        let mut online = self.0.lock().online.subscribe();
+        let offset = Duration::from_secs(beacon.slot_offset_secs);
+        let interval = Duration::from_secs(60 * beacon.beacon_interval_mins);
+        loop {
+            //pause while offline, the beacon goes out in its slot after every (re)connect
+            while !*online.borrow_and_update() {
+                if online.changed().await.is_err() {
+                    return;
                 }
-                tokio::time::sleep(Duration::from_secs(60 * cfg.beacon_interval_mins)).await;
             }
-        });
+            tokio::select! {
+                _ = tokio::time::sleep(offset) => {}
+                _ = online.changed() => continue,
+            }
+            if let Err(e) = self.send(&beacon).await {
+                self.error(&format!("failed to send beacon of {}: {}", beacon.ssid, e));
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(interval.saturating_sub(offset)) => {}
+                _ = online.changed() => {}
+            }
+        }
     }
-    async fn send(&self) -> Result<(), Box<dyn Error>> {
+    async fn send(&self, beacon: &Beacon) -> Result<(), Box<dyn Error>> {
         let writer = {
             if let Some(writer) = self.0.lock().own_writer.clone() {
                 writer
@@ -76,25 +195,104 @@ impl FixedBeacon {
                 return Ok(());
             }
         };
-        let cfg = &crate::Config::get().extensions.fixed_beacon;
         let package = format!(
-            "{ssid}>AP4GNT,TCPIP*,qAC,APRSAGENT:!{lat}{symbol_table}{lon}{symbol}{comment}\n",
-            ssid = cfg.ssid.to_uppercase(),
-            lat = cfg.lat,
-            symbol_table = cfg.symbol_table,
-            lon = cfg.lon,
-            symbol = cfg.symbol,
-            comment = cfg.comment
+            "{ssid}>{destination}{path}:!{info}\n",
+            ssid = beacon.ssid.to_uppercase(),
+            destination = beacon.destination.to_uppercase(),
+            path = if beacon.path.is_empty() {
+                String::new()
+            } else {
+                format!(",{}", beacon.path)
+            },
+            info = beacon.info()
         );
         writer.send(package.into_bytes()).await?;
         Ok(())
     }
 }
 
-#[derive(Default)]
+fn validate(b: &Beacon) {
+    if b.ssid.len() > 9 {
+        panic!("ssid cannot be longer than 9 characters");
+    }
+    if b.destination.is_empty() || b.destination.len() > 9 {
+        panic!("destination of {} must be 1 to 9 characters", b.ssid);
+    }
+    if b.path.split(',').count() > 8 {
+        panic!("path of {} cannot have more than 8 hops", b.ssid);
+    }
+    if b.latitude.is_some() != b.longitude.is_some() {
+        panic!(
+            "position of {} needs both latitude and longitude when set as decimal",
+            b.ssid
+        );
+    }
+    match b.position() {
+        Some((lat, lon)) if lat.is_finite() && lon.is_finite() => {
+            if !position::valid_lat_lon(lat, lon) {
+                panic!("position of {} is out of range: {lat}, {lon}", b.ssid);
+            }
+        }
+        _ => panic!(
+            "position of {} must be set as decimal latitude and longitude or as lat `DDMM.mmN` and lon `DDDMM.mmE`",
+            b.ssid
+        ),
+    }
+    let extensions = [
+        b.course_speed.is_some(),
+        b.phg.is_some(),
+        b.range_miles.is_some(),
+    ];
+    if extensions.iter().filter(|x| **x).count() > 1 {
+        panic!(
+            "beacon {} can only have one of course_speed, phg and range_miles",
+            b.ssid
+        );
+    }
+    if b.compressed && b.phg.is_some() {
+        panic!(
+            "beacon {} cannot send phg with a compressed position",
+            b.ssid
+        );
+    }
+    if let Some(cs) = b.course_speed {
+        if cs.course > 360 || cs.speed_knots > 999 {
+            panic!("course of {} must be 0-360 and speed 0-999 knots", b.ssid);
+        }
+    }
+    if let Some(phg) = b.phg {
+        if phg.power_watts > 81
+            || phg.height_feet > 5120
+            || phg.gain_db > 9
+            || phg.directivity > 360
+            || phg.directivity % 45 != 0
+        {
+            panic!(
+                "phg of {} must be at most 81 W, 5120 ft and 9 dB with directivity in steps of 45",
+                b.ssid
+            );
+        }
+    }
+    if b.range_miles.is_some_and(|r| r > 9999) {
+        panic!("range of {} cannot be more than 9999 miles", b.ssid);
+    }
+    if b.altitude_feet
+        .is_some_and(|a| !(-99999..=999999).contains(&a))
+    {
+        panic!("altitude of {} does not fit in /A=", b.ssid);
+    }
+    if b.beacon_interval_mins == 0 || b.slot_offset_secs >= 60 * b.beacon_interval_mins {
+        panic!(
+            "beacon interval of {} must be positive and longer than its slot offset",
+            b.ssid
+        );
+    }
+}
+
 struct FixedBeaconInner {
     own_writer: Option<tokio::sync::mpsc::Sender<Vec<u8>>>,
-    is_worker_running: bool,
+    online: watch::Sender<bool>,
+    beacons: Vec<Beacon>,
 }
 
 #[async_trait::async_trait]
@@ -106,10 +304,20 @@ impl Extension for FixedBeacon {
         None
     }
     fn set_own_writer(&self, w: tokio::sync::mpsc::Sender<Vec<u8>>) {
-        let mut inner = self.0.lock();
-        inner.own_writer = Some(w);
-        if !inner.is_worker_running {
-            self.start();
+        self.0.lock().own_writer = Some(w);
+    }
+    fn on_start(&self) {
+        for beacon in self.0.lock().beacons.clone() {
+            let ext = self.clone();
+            tokio::spawn(async move { ext.run(beacon).await });
         }
     }
+    fn on_connected(&self, _server: &str, _verified: bool) {
+        self.0.lock().online.send_replace(true);
+    }
+    fn on_disconnected(&self, _reason: &str) {
+        let mut inner = self.0.lock();
+        inner.own_writer = None;
+        inner.online.send_replace(false);
+    }
 }
@@ -1,6 +1,8 @@
 use educe::Educe;
 use serde::{Deserialize, Serialize};
 
+use super::filter::Filter;
+
 #[derive(Debug, Clone, Serialize, Deserialize, Educe)]
 #[educe(Default)]
 #[serde(default)]
@@ -15,6 +17,7 @@ pub struct Config {
     pub filter_by_message_type: Vec<char>,
     pub exclude_by_message_type: Vec<char>,
     pub keyword_filter: Vec<String>,
+    pub filter: Filter,
 }
 
 pub struct Logger;
@@ -0,0 +1,224 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use educe::Educe;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+
+use super::{filter::Filter, twitter::fmt_pass, Extension};
+use crate::aprs::{messaging::Messenger, packet::Packet};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    Public,
+    #[default]
+    Unlisted,
+    Private,
+    Direct,
+}
+impl Visibility {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Public => "public",
+            Self::Unlisted => "unlisted",
+            Self::Private => "private",
+            Self::Direct => "direct",
+        }
+    }
+}
+
+/// posts aprs messages from allowed senders to allowed addressees as mastodon statuses
+#[derive(Serialize, Deserialize, Clone, Educe)]
+#[educe(Default, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub enabled: bool,
+    #[educe(Default = "https://mastodon.social")]
+    pub instance_url: String,
+    #[educe(Debug(method = "fmt_pass"))]
+    pub access_token: String,
+    pub visibility: Visibility,
+    /// content warning shown in front of every status
+    pub spoiler_text: Option<String>,
+    #[educe(Default = true)]
+    pub add_hash_tag: bool,
+    #[educe(Default(expression = r#"vec!["TOOT"].into_iter().map(Into::into).collect()"#))]
+    pub allowed_recipients: Vec<String>,
+    #[educe(Default(expression = r#"vec!["N0CALL"].into_iter().map(Into::into).collect()"#))]
+    pub allowed_senders: Vec<String>,
+    #[educe(Default = 30)]
+    pub timeout_secs: u64,
+    pub filter: Filter,
+}
+
+#[derive(Clone)]
+pub struct Mastodon(Arc<Inner>);
+struct Inner {
+    cfg: Config,
+    client: reqwest::Client,
+    messenger: Messenger,
+    /// sender and message id of messages being posted, retransmits of them are ignored
+    queued: Mutex<HashSet<(String, String)>>,
+    /// posts still running, awaited on shutdown so their acks go out
+    posts: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Mastodon {
+    pub fn new(cfg: &Config) -> Self {
+        if cfg.access_token.is_empty() {
+            panic!("mastodon extension enabled but no access token specified");
+        }
+        if reqwest::Url::parse(&cfg.instance_url).is_err() {
+            panic!(
+                "mastodon extension has an invalid instance url {}",
+                cfg.instance_url
+            );
+        }
+        if cfg.allowed_recipients.is_empty() || cfg.allowed_senders.is_empty() {
+            panic!("mastodon extension enabled but no allowed recipients or senders specified");
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(cfg.timeout_secs))
+            .build()
+            .expect("failed to build http client");
+        Self(Arc::new(Inner {
+            cfg: cfg.clone(),
+            client,
+            messenger: Messenger::default(),
+            queued: Mutex::new(HashSet::new()),
+            posts: Mutex::new(vec![]),
+        }))
+    }
+    async fn post_status(
+        &self,
+        status: String,
+        idempotency_key: Option<String>,
+    ) -> Result<(), String> {
+        let cfg = &self.0.cfg;
+        let status = if cfg.add_hash_tag {
+            format!("{status} #APRS")
+        } else {
+            status
+        };
+        let mut form = vec![
+            ("status", status),
+            ("visibility", cfg.visibility.as_str().to_string()),
+        ];
+        if let Some(ref spoiler) = cfg.spoiler_text {
+            form.push(("spoiler_text", spoiler.clone()));
+        }
+        let mut req = self
+            .0
+            .client
+            .post(format!(
+                "{}/api/v1/statuses",
+                cfg.instance_url.trim_end_matches('/')
+            ))
+            .bearer_auth(&cfg.access_token)
+            .form(&form);
+        //stations retransmit until they get an ack, the key makes the instance return the
+        //already created status instead of posting it again
+        if let Some(key) = idempotency_key {
+            req = req.header("Idempotency-Key", key);
+        }
+        let resp = req.send().await.map_err(|e| e.to_string())?;
+        let status = resp.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{status}: {}",
+                resp.text().await.unwrap_or_default()
+            ))
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for Mastodon {
+    fn name(&self) -> &'static str {
+        "mastodon"
+    }
+    async fn handle(&self, line: &str) -> Option<Vec<u8>> {
+        let cfg = &self.0.cfg;
+        let packet = Packet::parse(line)?;
+        let (recipient, text) = (packet.addressee.clone()?, packet.message.clone()?);
+        if packet.kind() != "message" || text.is_empty() {
+            return None;
+        }
+        let sender_callsign = packet.from.split('-').next().unwrap_or_default();
+        if !cfg
+            .allowed_senders
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(sender_callsign))
+        {
+            return None;
+        }
+        if !cfg
+            .allowed_recipients
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(&recipient))
+        {
+            return None;
+        }
+        let status = format!(
+            "{text}\nfrom {}>{},{}",
+            packet.from,
+            packet.to,
+            packet.path.join(",")
+        );
+        if self.0.messenger.already_handled(&packet) {
+            self.0.messenger.ack(&packet).await;
+            return None;
+        }
+        let queue_key = packet
+            .message_id
+            .as_ref()
+            .map(|id| (packet.from.to_uppercase(), id.clone()));
+        if let Some(ref key) = queue_key {
+            if !self.0.queued.lock().insert(key.clone()) {
+                return None;
+            }
+        }
+        let key = packet
+            .message_id
+            .as_ref()
+            .map(|id| format!("{}-{id}", packet.from));
+        //the post can take a while, the ack goes out through the own writer once it succeeded
+        let ext = self.clone();
+        let post = tokio::spawn(async move {
+            match ext.post_status(status, key).await {
+                Ok(()) => {
+                    ext.0.messenger.mark_handled(&packet);
+                    ext.0.messenger.ack(&packet).await;
+                }
+                Err(e) => ext.error(&format!("failed to post status: {e}")),
+            }
+            if let Some(key) = queue_key {
+                ext.0.queued.lock().remove(&key);
+            }
+        });
+        let mut posts = self.0.posts.lock();
+        posts.retain(|p| !p.is_finished());
+        posts.push(post);
+        None
+    }
+    fn set_own_writer(&self, w: mpsc::Sender<Vec<u8>>) {
+        self.0.messenger.set_writer(Some(w));
+    }
+    fn on_disconnected(&self, _reason: &str) {
+        self.0.messenger.set_writer(None);
+    }
+    async fn on_shutdown(&self, deadline: Instant) {
+        let posts = std::mem::take(&mut *self.0.posts.lock());
+        let wait = async {
+            for post in posts {
+                post.await.ok();
+            }
+        };
+        if tokio::time::timeout_at(deadline, wait).await.is_err() {
+            self.warn("posts still running at the shutdown deadline, their messages are not acked");
+        }
+    }
+}
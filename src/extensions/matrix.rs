@@ -0,0 +1,341 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use educe::Educe;
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::{filter::Filter, twitter::fmt_pass, Extension};
+use crate::aprs::{
+    messaging::{Messenger, Outcome},
+    packet::Packet,
+};
+
+/// bridges aprs messages addressed to `alias` into matrix rooms and back
+/// room messages in the form `@CALLSIGN text` from allowed users are sent to that station
+#[derive(Serialize, Deserialize, Clone, Educe)]
+#[educe(Default, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub enabled: bool,
+    #[educe(Default = "https://matrix.org")]
+    pub homeserver_url: String,
+    #[educe(Debug(method = "fmt_pass"))]
+    pub access_token: String,
+    /// room ids or aliases the bot joins on startup
+    pub rooms: Vec<String>,
+    /// aprs stations message this name to reach the rooms, replies are sent from it
+    #[educe(Default = "MATRIX")]
+    pub alias: String,
+    #[educe(Default(expression = r#"vec!["N0CALL"].into_iter().map(Into::into).collect()"#))]
+    pub allowed_senders: Vec<String>,
+    /// matrix user ids like `@alice:example.org` allowed to send aprs messages
+    pub allowed_users: Vec<String>,
+    #[educe(Default = 30)]
+    pub sync_timeout_secs: u64,
+    /// first retransmit of an unacked aprs message, doubled after every attempt
+    #[educe(Default = 30)]
+    pub retry_secs: u64,
+    #[educe(Default = 5)]
+    pub max_attempts: u32,
+    pub filter: Filter,
+}
+
+#[derive(Deserialize)]
+struct Joined {
+    room_id: String,
+}
+#[derive(Deserialize)]
+struct Sync {
+    next_batch: String,
+    #[serde(default)]
+    rooms: Rooms,
+}
+#[derive(Deserialize, Default)]
+struct Rooms {
+    #[serde(default)]
+    join: HashMap<String, JoinedRoom>,
+}
+#[derive(Deserialize)]
+struct JoinedRoom {
+    timeline: Timeline,
+}
+#[derive(Deserialize)]
+struct Timeline {
+    #[serde(default)]
+    events: Vec<Event>,
+}
+#[derive(Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    kind: String,
+    sender: String,
+    #[serde(default)]
+    content: serde_json::Value,
+}
+
+#[derive(Clone)]
+pub struct Matrix(Arc<Inner>);
+struct Inner {
+    cfg: Config,
+    client: reqwest::Client,
+    messenger: Messenger,
+    /// ids of the rooms joined so far
+    rooms: Mutex<Vec<String>>,
+    txn: AtomicU64,
+    /// sender and message id of messages being posted, retransmits of them are ignored
+    queued: Mutex<HashSet<(String, String)>>,
+}
+
+impl Matrix {
+    pub fn new(cfg: &Config) -> Self {
+        if cfg.access_token.is_empty() {
+            panic!("matrix extension enabled but no access token specified");
+        }
+        if cfg.rooms.is_empty() || cfg.allowed_senders.is_empty() {
+            panic!("matrix extension enabled but no rooms or allowed senders specified");
+        }
+        if reqwest::Url::parse(&cfg.homeserver_url).is_err() {
+            panic!(
+                "matrix extension has an invalid homeserver url {}",
+                cfg.homeserver_url
+            );
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(cfg.sync_timeout_secs + 10))
+            .build()
+            .expect("failed to build http client");
+        Self(Arc::new(Inner {
+            cfg: cfg.clone(),
+            client,
+            messenger: Messenger::new(cfg.retry_secs, cfg.max_attempts),
+            rooms: Mutex::new(vec![]),
+            txn: AtomicU64::new(crate::utils::now_unix()),
+            queued: Mutex::new(HashSet::new()),
+        }))
+    }
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        query: &[(&str, String)],
+        body: Option<serde_json::Value>,
+    ) -> Result<T, String> {
+        let cfg = &self.0.cfg;
+        let url = format!(
+            "{}/_matrix/client/v3{path}",
+            cfg.homeserver_url.trim_end_matches('/')
+        );
+        let mut req = self
+            .0
+            .client
+            .request(method, url)
+            .bearer_auth(&cfg.access_token)
+            .query(query);
+        if let Some(body) = body {
+            req = req
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string());
+        }
+        let resp = req.send().await.map_err(|e| e.to_string())?;
+        let status = resp.status();
+        let text = resp.text().await.map_err(|e| e.to_string())?;
+        if !status.is_success() {
+            return Err(format!("{status}: {text}"));
+        }
+        serde_json::from_str(&text).map_err(|e| e.to_string())
+    }
+    async fn send_to_room(&self, room: &str, text: &str) -> Result<(), String> {
+        let txn = self.0.txn.fetch_add(1, Ordering::Relaxed);
+        let path = format!("/rooms/{}/send/m.room.message/{txn}", urlencode(room));
+        let body = serde_json::json!({ "msgtype": "m.text", "body": text });
+        self.call::<serde_json::Value>(reqwest::Method::PUT, &path, &[], Some(body))
+            .await
+            .map(|_| ())
+    }
+    async fn join_rooms(&self) {
+        for room in &self.0.cfg.rooms {
+            let path = format!("/join/{}", urlencode(room));
+            match self
+                .call::<Joined>(
+                    reqwest::Method::POST,
+                    &path,
+                    &[],
+                    Some(serde_json::json!({})),
+                )
+                .await
+            {
+                Ok(joined) => {
+                    self.log(&format!("joined {room}"));
+                    self.0.rooms.lock().push(joined.room_id);
+                }
+                Err(e) => self.error(&format!("failed to join {room}: {e}")),
+            }
+        }
+    }
+    async fn sync(&self) {
+        self.join_rooms().await;
+        let mut since = None::<String>;
+        loop {
+            let mut query = vec![("timeout", (self.0.cfg.sync_timeout_secs * 1000).to_string())];
+            match since {
+                Some(ref since) => query.push(("since", since.clone())),
+                //the first sync only fetches the position so old room history is not replayed
+                None => query.push(("filter", r#"{"room":{"timeline":{"limit":0}}}"#.into())),
+            }
+            let sync = match self
+                .call::<Sync>(reqwest::Method::GET, "/sync", &query, None)
+                .await
+            {
+                Ok(sync) => sync,
+                Err(e) => {
+                    self.error(&format!("sync failed: {e}"));
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+            if since.is_some() {
+                let rooms = self.0.rooms.lock().clone();
+                for (room, joined) in sync.rooms.join {
+                    if !rooms.contains(&room) {
+                        continue;
+                    }
+                    for event in joined.timeline.events {
+                        self.relay(&room, event);
+                    }
+                }
+            }
+            since = Some(sync.next_batch);
+        }
+    }
+    /// sends a room message to the station it names, the room is told how the delivery went
+    fn relay(&self, room: &str, event: Event) {
+        if event.kind != "m.room.message" {
+            return;
+        }
+        let Some(body) = event.content.get("body").and_then(|b| b.as_str()) else {
+            return;
+        };
+        let Some((to, text)) = body.strip_prefix('@').and_then(|b| b.split_once(' ')) else {
+            return;
+        };
+        //matrix mentions start with @ as well but always carry a server name
+        let is_callsign =
+            to.len() <= 9 && to.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !is_callsign || text.trim().is_empty() {
+            return;
+        }
+        if !self.0.cfg.allowed_users.iter().any(|u| u == &event.sender) {
+            self.warn(&format!("ignoring message from {}", event.sender));
+            return;
+        }
+        let (room, to, text) = (room.to_string(), to.to_uppercase(), text.trim().to_string());
+        let ext = self.clone();
+        tokio::spawn(async move {
+            let outcome = ext.0.messenger.send(&ext.0.cfg.alias, &to, &text).await;
+            let status = match outcome {
+                Outcome::Acked => format!("delivered to {to}"),
+                Outcome::Rejected => format!("rejected by {to}"),
+                Outcome::Expired => format!("no ack from {to}, message not delivered"),
+            };
+            if let Err(e) = ext.send_to_room(&room, &status).await {
+                ext.error(&format!("failed to report delivery: {e}"));
+            }
+        });
+    }
+}
+
+/// percent encodes a path segment, room aliases contain `#` and `:`
+fn urlencode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl Extension for Matrix {
+    fn name(&self) -> &'static str {
+        "matrix"
+    }
+    async fn handle(&self, line: &str) -> Option<Vec<u8>> {
+        let cfg = &self.0.cfg;
+        let packet = Packet::parse(line)?;
+        if self.0.messenger.handle_reply(&packet) || packet.kind() != "message" {
+            return None;
+        }
+        if !packet.addressee.as_ref()?.eq_ignore_ascii_case(&cfg.alias) {
+            return None;
+        }
+        let sender_callsign = packet.from.split('-').next().unwrap_or_default();
+        if !cfg
+            .allowed_senders
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(sender_callsign))
+        {
+            return None;
+        }
+        let text = packet.message.clone()?;
+        if self.0.messenger.already_handled(&packet) {
+            self.0.messenger.ack(&packet).await;
+            return None;
+        }
+        let rooms = self.0.rooms.lock().clone();
+        if rooms.is_empty() {
+            self.warn("no room joined yet, message not acked");
+            return None;
+        }
+        //marked before posting, a retransmit arriving meanwhile must not be posted twice
+        let queue_key = queue_key(&packet);
+        if let Some(ref key) = queue_key {
+            if !self.0.queued.lock().insert(key.clone()) {
+                return None;
+            }
+        }
+        let ext = self.clone();
+        tokio::spawn(async move {
+            let text = format!("{}: {text}", packet.from);
+            let mut delivered = false;
+            for room in rooms {
+                match ext.send_to_room(&room, &text).await {
+                    Ok(()) => delivered = true,
+                    Err(e) => ext.error(&format!("failed to post to {room}: {e}")),
+                }
+            }
+            if delivered {
+                ext.0.messenger.mark_handled(&packet);
+                ext.0.messenger.ack(&packet).await;
+            }
+            if let Some(key) = queue_key {
+                ext.0.queued.lock().remove(&key);
+            }
+        });
+        None
+    }
+    fn set_own_writer(&self, w: mpsc::Sender<Vec<u8>>) {
+        self.0.messenger.set_writer(Some(w));
+    }
+    fn on_start(&self) {
+        let ext = self.clone();
+        tokio::spawn(async move { ext.sync().await });
+    }
+    fn on_disconnected(&self, _reason: &str) {
+        self.0.messenger.set_writer(None);
+    }
+}
+
+fn queue_key(packet: &Packet) -> Option<(String, String)> {
+    Some((packet.from.to_uppercase(), packet.message_id.clone()?))
+}
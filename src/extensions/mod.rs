@@ -1,23 +1,55 @@
+use std::{ops::Deref, time::Duration};
+
 use async_trait::async_trait;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    time::Instant,
+};
+pub mod breaker;
+pub mod email_gateway;
+pub mod filter;
 pub mod fixed_beacon;
 pub mod logger;
+pub mod mastodon;
+pub mod matrix;
+pub mod mqtt;
+pub mod objects;
+pub mod outbound;
+pub mod process;
+pub mod queue;
+pub mod script;
 pub mod smtp;
+pub mod status;
+#[cfg(test)]
+mod stub_http;
+pub mod telegram;
 pub mod twitter;
+pub mod wasm;
+pub mod webhook;
 
 #[async_trait]
 pub trait Extension {
     fn name(&self) -> &'static str;
     async fn handle(&self, line: &str) -> Option<Vec<u8>>;
-    /// if an extension is spawnable it will be spawned in a new tokio task
-    /// this is useful for extensions that for sure will not return something to the aprs server
+    /// if an extension is spawnable its lines are queued and handled by a pool of worker tasks
+    /// see `extension_runtime.queue` in the config, this is useful for extensions that for sure will not return something to the aprs server
     /// or that has an own writer
     fn is_spawnable(&self) -> bool {
         false
     }
     /// set own writer is used for extensions that need to write data back to the aprs server without getting a message first
     /// this is used for example by an extension that sends fixed position packets every x minutes
+    /// it is called on every (re)connect before on_connected with a writer bound to the new connection
     fn set_own_writer(&self, _: tokio::sync::mpsc::Sender<Vec<u8>>) {}
+    /// called once before the first connection attempt, the place to spawn background workers
+    fn on_start(&self) {}
+    /// called after aprs-is answered the login, verified is false when the passcode was not accepted
+    fn on_connected(&self, _server: &str, _verified: bool) {}
+    /// called whenever the upstream connection is lost, the own writer is useless until the next on_connected
+    fn on_disconnected(&self, _reason: &str) {}
+    /// called once when the agent is shutting down after all spawned handlers finished
+    /// extensions should flush whatever they have queued, the own writer is still drained until the deadline
+    async fn on_shutdown(&self, _deadline: Instant) {}
     fn log(&self, msg: &str) {
         eprintln!("\x1B[32m{}:\x1B[0m {}", self.name(), msg);
     }
@@ -29,16 +61,44 @@ pub trait Extension {
     }
 }
 
-static mut EXTENSIONS: Option<Vec<Box<dyn Extension + Send + Sync>>> = None;
+static mut EXTENSIONS: Option<Vec<Registered>> = None;
+/// a registered extension together with the inbound filter from its config
+struct Registered {
+    ext: Box<dyn Extension + Send + Sync>,
+    filter: filter::Compiled,
+    breaker: breaker::CircuitBreaker,
+    /// spawnable extensions are fed through a bounded queue drained by their own workers
+    queue: Option<queue::WorkQueue>,
+}
+impl Deref for Registered {
+    type Target = dyn Extension + Send + Sync;
+    fn deref(&self) -> &Self::Target {
+        &*self.ext
+    }
+}
 pub struct ExtensionRegistry;
 impl ExtensionRegistry {
     pub fn register(ext: impl Extension + 'static + Send + Sync) {
+        Self::register_filtered(ext, &Default::default());
+    }
+    /// registers an extension whose handle is only called for lines matching the filter
+    pub fn register_filtered(ext: impl Extension + 'static + Send + Sync, filter: &filter::Filter) {
+        let queue = ext.is_spawnable().then(|| {
+            let cfg = &crate::Config::get().extension_runtime;
+            queue::WorkQueue::new(cfg.queues.get(ext.name()).unwrap_or(&cfg.queue).clone())
+        });
+        let ext = Registered {
+            filter: filter.compile(ext.name()),
+            ext: Box::new(ext),
+            breaker: Default::default(),
+            queue,
+        };
         unsafe {
             ext.log("extension is being activated");
             if let Some(ref mut exts) = EXTENSIONS {
-                exts.push(Box::new(ext));
+                exts.push(ext);
             } else {
-                EXTENSIONS = Some(vec![Box::new(ext)]);
+                EXTENSIONS = Some(vec![ext]);
             }
         }
     }
@@ -49,13 +109,19 @@ impl ExtensionRegistry {
     ) -> Result<(), std::io::Error> {
         unsafe {
             if let Some(ref exts) = EXTENSIONS {
+                let parsed = filter::Line::new(line);
                 for ext in exts {
-                    if ext.is_spawnable() {
-                        let line = line.to_owned();
-                        tokio::spawn(async move {
-                            ext.handle(&line).await;
-                        });
-                    } else if let Some(mut res) = ext.handle(line).await {
+                    if !ext.filter.matches(&parsed) {
+                        continue;
+                    }
+                    if let Some(ref queue) = ext.queue {
+                        if queue.push(line.to_owned()).await.is_some() {
+                            let dropped = queue.dropped();
+                            if dropped == 1 || dropped % 1000 == 0 {
+                                ext.warn(&format!("queue full, {dropped} lines dropped so far"));
+                            }
+                        }
+                    } else if let Some(mut res) = Self::invoke(ext, line.to_owned()).await {
                         eprintln!(
                             "extension {} writing to aprs server:\n{}\n-----",
                             ext.name(),
@@ -83,6 +149,43 @@ impl ExtensionRegistry {
         }
         Ok(())
     }
+    /// runs a single handle call in its own task so a panic only fails this call
+    /// and bounds it by the configured timeout, both count towards the extension's circuit breaker
+    async fn invoke(ext: &'static Registered, line: String) -> Option<Vec<u8>> {
+        if !ext.breaker.allows() {
+            return None;
+        }
+        let timeout = Duration::from_secs(crate::Config::get().extension_runtime.timeout_secs);
+        let task = tokio::spawn(async move { ext.handle(&line).await });
+        let abort = task.abort_handle();
+        match tokio::time::timeout(timeout, task).await {
+            Ok(Ok(res)) => {
+                ext.breaker.record_success();
+                res
+            }
+            Ok(Err(e)) => {
+                let reason = if e.is_panic() {
+                    let panic = e.into_panic();
+                    panic
+                        .downcast_ref::<&str>()
+                        .map(ToString::to_string)
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "unknown panic".to_string())
+                } else {
+                    e.to_string()
+                };
+                ext.error(&format!("handler failed: {reason}"));
+                ext.breaker.record_failure(&**ext);
+                None
+            }
+            Err(_) => {
+                abort.abort();
+                ext.error(&format!("handler timed out after {}s", timeout.as_secs()));
+                ext.breaker.record_failure(&**ext);
+                None
+            }
+        }
+    }
     pub fn set_own_writers(w: tokio::sync::mpsc::Sender<Vec<u8>>) {
         unsafe {
             if let Some(ref exts) = EXTENSIONS {
@@ -92,6 +195,94 @@ impl ExtensionRegistry {
             }
         }
     }
+    pub fn start() {
+        unsafe {
+            if let Some(ref exts) = EXTENSIONS {
+                for ext in exts {
+                    if let Some(ref queue) = ext.queue {
+                        for _ in 0..queue.workers() {
+                            tokio::spawn(async move {
+                                loop {
+                                    let (line, _processing) = queue.pop().await;
+                                    Self::invoke(ext, line).await;
+                                }
+                            });
+                        }
+                    }
+                    ext.on_start();
+                }
+            }
+        }
+    }
+    /// lines dropped so far by each spawnable extension's queue
+    pub fn dropped_lines() -> Vec<(&'static str, u64)> {
+        let mut dropped = vec![];
+        unsafe {
+            if let Some(ref exts) = EXTENSIONS {
+                for ext in exts {
+                    if let Some(ref queue) = ext.queue {
+                        dropped.push((ext.name(), queue.dropped()));
+                    }
+                }
+            }
+        }
+        dropped
+    }
+    fn pending() -> usize {
+        let mut pending = 0;
+        unsafe {
+            if let Some(ref exts) = EXTENSIONS {
+                for ext in exts {
+                    if let Some(ref queue) = ext.queue {
+                        pending += queue.pending();
+                    }
+                }
+            }
+        }
+        pending
+    }
+    pub fn connected(server: &str, verified: bool) {
+        unsafe {
+            if let Some(ref exts) = EXTENSIONS {
+                for ext in exts {
+                    ext.on_connected(server, verified);
+                }
+            }
+        }
+    }
+    pub fn disconnected(reason: &str) {
+        unsafe {
+            if let Some(ref exts) = EXTENSIONS {
+                for ext in exts {
+                    ext.on_disconnected(reason);
+                }
+            }
+        }
+    }
+    /// waits for the queues of spawnable extensions to drain and then runs every extension's shutdown hook
+    /// both steps are bounded by the deadline
+    pub async fn shutdown(deadline: Instant) {
+        while Self::pending() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let pending = Self::pending();
+        if pending > 0 {
+            eprintln!("{pending} extension handlers still running at shutdown deadline");
+        }
+        let mut hooks = vec![];
+        unsafe {
+            if let Some(ref exts) = EXTENSIONS {
+                for ext in exts {
+                    hooks.push((ext.name(), tokio::spawn(ext.on_shutdown(deadline))));
+                }
+            }
+        }
+        for (name, hook) in hooks {
+            if tokio::time::timeout_at(deadline, hook).await.is_err() {
+                eprintln!("extension {name} did not finish its shutdown before the deadline");
+            }
+        }
+    }
 }
 
 #[async_trait]
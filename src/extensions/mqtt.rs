@@ -0,0 +1,226 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use educe::Educe;
+use parking_lot::Mutex;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, QoS, Transport};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+
+use super::{filter::Filter, Extension};
+use crate::{aprs::packet::Packet, utils::render_template};
+
+/// publishes decoded packets to an mqtt broker as json
+/// messages published to `subscribe_topic` are sent as aprs messages, the payload is either
+/// `{"to":"N0CALL-7","text":"hi"}` or plain text in the form `N0CALL-7 hi`
+#[derive(Debug, Serialize, Deserialize, Clone, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct Config {
+    pub enabled: bool,
+    #[educe(Default = "127.0.0.1")]
+    pub host: String,
+    #[educe(Default = 1883)]
+    pub port: u16,
+    #[educe(Default = "aprs-agent")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: bool,
+    /// pem encoded ca certificate, the system roots are used when unset
+    pub ca_file: Option<PathBuf>,
+    /// 0 at most once, 1 at least once, 2 exactly once
+    #[educe(Default = 0)]
+    pub qos: u8,
+    #[educe(Default = 30)]
+    pub keep_alive_secs: u64,
+    #[educe(Default = 5)]
+    pub reconnect_delay_secs: u64,
+    /// topic with `{field}` placeholders of the packet, `{type}` is the packet kind
+    #[educe(Default = "aprs/{from}/{type}")]
+    pub topic_template: String,
+    /// positions are also published here as retained messages so subscribers see the last one
+    #[educe(Default(expression = r#"Some("aprs/position/{from}".into())"#))]
+    pub position_topic_template: Option<String>,
+    pub subscribe_topic: Option<String>,
+    pub filter: Filter,
+}
+
+#[derive(Deserialize)]
+struct Outgoing {
+    to: String,
+    text: String,
+}
+
+#[derive(Clone)]
+pub struct Mqtt(Arc<Inner>);
+struct Inner {
+    cfg: Config,
+    qos: QoS,
+    client: AsyncClient,
+    eventloop: Mutex<Option<EventLoop>>,
+    /// the task driving the connection, awaited on shutdown until the disconnect went out
+    task: Mutex<Option<JoinHandle<()>>>,
+    own_writer: Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+}
+
+impl Mqtt {
+    pub fn new(cfg: &Config) -> Self {
+        let qos = match cfg.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            q => panic!("mqtt qos must be 0, 1 or 2, got {q}"),
+        };
+        let mut opts = MqttOptions::new(&cfg.client_id, &cfg.host, cfg.port);
+        opts.set_keep_alive(Duration::from_secs(cfg.keep_alive_secs.max(5)));
+        if let Some(ref username) = cfg.username {
+            opts.set_credentials(username, cfg.password.clone().unwrap_or_default());
+        }
+        if cfg.tls {
+            opts.set_transport(match cfg.ca_file {
+                Some(ref path) => Transport::tls(
+                    std::fs::read(path)
+                        .unwrap_or_else(|e| panic!("failed to read mqtt ca file {path:?}: {e}")),
+                    None,
+                    None,
+                ),
+                None => Transport::tls_with_default_config(),
+            });
+        }
+        let (client, eventloop) = AsyncClient::new(opts, 256);
+        Self(Arc::new(Inner {
+            cfg: cfg.clone(),
+            qos,
+            client,
+            eventloop: Mutex::new(Some(eventloop)),
+            task: Mutex::new(None),
+            own_writer: Mutex::new(None),
+        }))
+    }
+    fn publish(&self, topic: String, retain: bool, payload: &str) {
+        if let Err(e) = self
+            .0
+            .client
+            .try_publish(topic, self.0.qos, retain, payload.as_bytes())
+        {
+            self.warn(&format!("dropping packet, broker is not keeping up: {e}"));
+        }
+    }
+    /// drives the connection, rumqttc reconnects on the next poll after an error
+    /// returns once the disconnect requested on shutdown was written to the broker
+    async fn run(&self, mut eventloop: EventLoop) {
+        let delay = Duration::from_secs(self.0.cfg.reconnect_delay_secs);
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                    self.log(&format!(
+                        "connected to {}:{}",
+                        self.0.cfg.host, self.0.cfg.port
+                    ));
+                    //subscriptions do not survive a clean session so they are renewed on every connect
+                    if let Some(ref topic) = self.0.cfg.subscribe_topic {
+                        if let Err(e) = self.0.client.try_subscribe(topic, self.0.qos) {
+                            self.error(&format!("failed to subscribe to {topic}: {e}"));
+                        }
+                    }
+                }
+                Ok(Event::Incoming(rumqttc::Packet::Publish(p))) => {
+                    self.transmit(&String::from_utf8_lossy(&p.payload)).await;
+                }
+                Ok(Event::Outgoing(rumqttc::Outgoing::Disconnect)) => return,
+                Ok(_) => {}
+                Err(e) => {
+                    self.error(&format!("connection error, reconnecting in {delay:?}: {e}"));
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+    async fn transmit(&self, payload: &str) {
+        let msg = match serde_json::from_str::<Outgoing>(payload) {
+            Ok(msg) => msg,
+            Err(_) => match payload.trim().split_once(char::is_whitespace) {
+                Some((to, text)) => Outgoing {
+                    to: to.to_string(),
+                    text: text.to_string(),
+                },
+                None => {
+                    self.warn(&format!("ignoring malformed message {payload:?}"));
+                    return;
+                }
+            },
+        };
+        if msg.to.is_empty() || msg.to.len() > 9 || msg.to.contains(['\r', '\n', ':']) {
+            self.warn(&format!("ignoring message to {:?}", msg.to));
+            return;
+        }
+        if msg.text.contains(['\r', '\n']) {
+            self.warn(&format!(
+                "ignoring message to {} spanning multiple lines",
+                msg.to
+            ));
+            return;
+        }
+        let Some(writer) = self.0.own_writer.lock().clone() else {
+            self.warn("not connected to aprs-is, dropping message");
+            return;
+        };
+        let callsign = &crate::Config::get().callsign;
+        for text in crate::aprs::split_message(&msg.text) {
+            let packet = crate::aprs::message_packet(callsign, &msg.to, &text, None);
+            if writer.send(packet.into_bytes()).await.is_err() {
+                self.warn("connection closed, dropping message");
+                return;
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for Mqtt {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+    async fn handle(&self, line: &str) -> Option<Vec<u8>> {
+        let packet = Packet::parse(line)?;
+        let json = serde_json::to_string(&packet).ok()?;
+        let topic = render_template(&self.0.cfg.topic_template, |name| packet.var(name));
+        self.publish(topic, false, &json);
+        if let (Some(template), Some(_)) = (&self.0.cfg.position_topic_template, packet.latitude) {
+            let topic = render_template(template, |name| packet.var(name));
+            self.publish(topic, true, &json);
+        }
+        None
+    }
+    fn set_own_writer(&self, w: mpsc::Sender<Vec<u8>>) {
+        *self.0.own_writer.lock() = Some(w);
+    }
+    fn on_start(&self) {
+        let Some(eventloop) = self.0.eventloop.lock().take() else {
+            return;
+        };
+        let ext = self.clone();
+        *self.0.task.lock() = Some(tokio::spawn(async move { ext.run(eventloop).await }));
+    }
+    fn on_disconnected(&self, _reason: &str) {
+        *self.0.own_writer.lock() = None;
+    }
+    async fn on_shutdown(&self, deadline: Instant) {
+        //requests are processed in order, once the event loop wrote the disconnect the publishes
+        //queued before it went out too
+        if tokio::time::timeout_at(deadline, self.0.client.disconnect())
+            .await
+            .is_err()
+        {
+            self.warn("broker did not take the disconnect before the shutdown deadline");
+        }
+        let Some(task) = self.0.task.lock().take() else {
+            return;
+        };
+        let abort = task.abort_handle();
+        if tokio::time::timeout_at(deadline, task).await.is_err() {
+            abort.abort();
+            self.warn("queued publishes not flushed before the shutdown deadline");
+        }
+    }
+}
@@ -0,0 +1,541 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use educe::Educe;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, time::Instant};
+
+use super::Extension;
+use crate::{
+    aprs::position::{self, CompressedExt},
+    utils::{aprs_timestamp, now_unix},
+};
+
+/// periodically transmits aprs objects and items like nets, repeaters and events
+/// objects removed from the config or the objects file are killed, changed ones are sent
+/// quickly at first and then less often until they reach their interval
+#[derive(Debug, Serialize, Deserialize, Clone, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct Config {
+    pub enabled: bool,
+    /// station the objects are sent from, the agent callsign when empty
+    pub source: String,
+    #[educe(Default = "AP4GNT")]
+    pub destination: String,
+    /// comma separated digipeater path after the destination
+    #[educe(Default = "TCPIP*")]
+    pub path: String,
+    pub objects: Vec<Object>,
+    /// toml file with more `[[objects]]`, reloaded whenever it changes
+    pub objects_file: Option<PathBuf>,
+    #[educe(Default = 5)]
+    pub reload_check_secs: u64,
+    /// remembers what was announced so objects removed while the agent was down are killed too
+    #[educe(Default = "objects-state.json")]
+    pub state_file: PathBuf,
+    #[educe(Default = 10)]
+    pub interval_mins: u64,
+    /// first retransmit after an object changed, doubled until it reaches the interval
+    #[educe(Default = 30)]
+    pub initial_interval_secs: u64,
+    /// how often the kill of a removed object is sent
+    #[educe(Default = 3)]
+    pub kill_count: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct Object {
+    pub name: String,
+    /// items carry no timestamp and are meant for things that do not move like a net or a cache
+    pub item: bool,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub compressed: bool,
+    #[educe(Default = '/')]
+    pub symbol_table: char,
+    #[educe(Default = 'r')]
+    pub symbol: char,
+    pub comment: String,
+    /// repeater frequency, sent in front of the comment as `146.940MHz`
+    pub frequency_mhz: Option<f64>,
+    /// ctcss tone, `T088` or with tone_squelch `C088`
+    pub tone_hz: Option<f64>,
+    pub tone_squelch: bool,
+    pub dcs: Option<u16>,
+    /// repeater input offset, `-600` is sent as `-060`
+    pub offset_khz: Option<i32>,
+    /// overrides the interval of the extension
+    pub interval_mins: Option<u64>,
+}
+impl Object {
+    /// frequency, tone and offset followed by the comment as described in the aprs frequency spec
+    fn comment_text(&self) -> String {
+        let mut parts = vec![];
+        if let Some(freq) = self.frequency_mhz {
+            parts.push(format!("{freq:07.3}MHz"));
+        }
+        if let Some(tone) = self.tone_hz {
+            let kind = if self.tone_squelch { 'C' } else { 'T' };
+            parts.push(format!("{kind}{:03}", tone as u32));
+        }
+        if let Some(dcs) = self.dcs {
+            parts.push(format!("D{dcs:03}"));
+        }
+        if let Some(offset) = self.offset_khz {
+            parts.push(format!("{:+04}", offset / 10));
+        }
+        if !self.comment.is_empty() {
+            parts.push(self.comment.clone());
+        }
+        parts.join(" ")
+    }
+    fn check(&self) -> Result<(), String> {
+        let name = &self.name;
+        let printable = name.chars().all(|c| (' '..='~').contains(&c));
+        if name.trim().is_empty() || name.len() > 9 || !printable {
+            return Err(format!(
+                "object name {name:?} must be 1 to 9 printable characters"
+            ));
+        }
+        if self.item && (name.len() < 3 || name.contains(['!', '_'])) {
+            return Err(format!(
+                "item name {name:?} must be at least 3 characters without ! or _"
+            ));
+        }
+        if !self.latitude.is_finite()
+            || !self.longitude.is_finite()
+            || !position::valid_lat_lon(self.latitude, self.longitude)
+        {
+            return Err(format!(
+                "position of {name} is out of range: {}, {}",
+                self.latitude, self.longitude
+            ));
+        }
+        if self
+            .frequency_mhz
+            .is_some_and(|f| !(0.0..1000.0).contains(&f))
+            || self.tone_hz.is_some_and(|t| !(0.0..1000.0).contains(&t))
+            || self.dcs.is_some_and(|d| d > 777)
+            || self.offset_khz.is_some_and(|o| o.abs() >= 10_000)
+        {
+            return Err(format!(
+                "frequency, tone, dcs or offset of {name} is out of range"
+            ));
+        }
+        if self.comment_text().chars().count() > 43 {
+            return Err(format!("comment of {name} is longer than 43 characters"));
+        }
+        if self.interval_mins == Some(0) {
+            return Err(format!("interval of {name} must be positive"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ObjectsFile {
+    objects: Vec<Object>,
+}
+
+/// transmission schedule of an announced object, killed ones stay until their kills are sent
+struct Schedule {
+    object: Object,
+    live: bool,
+    next: Instant,
+    step: Duration,
+    kills_left: u32,
+}
+
+#[derive(Clone)]
+pub struct Objects(Arc<Inner>);
+struct Inner {
+    cfg: Config,
+    own_writer: Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+    schedules: Mutex<HashMap<String, Schedule>>,
+    /// modification time of the objects file that was loaded last, none before the first load
+    file_modified: Mutex<Option<Option<SystemTime>>>,
+    /// objects from the file, the inline ones are added to them
+    file_objects: Mutex<Vec<Object>>,
+}
+
+impl Objects {
+    pub fn new(cfg: &Config) -> Self {
+        if !cfg.enabled {
+            panic!("objects extension is not enabled but tried to be created");
+        }
+        if cfg.interval_mins == 0 || cfg.kill_count == 0 {
+            panic!("objects extension needs a positive interval and kill count");
+        }
+        if cfg.destination.is_empty() || cfg.destination.len() > 9 {
+            panic!("objects destination must be 1 to 9 characters");
+        }
+        for (i, object) in cfg.objects.iter().enumerate() {
+            if let Err(e) = object.check() {
+                panic!("objects extension: {e}");
+            }
+            if cfg.objects[..i].iter().any(|o| o.name == object.name) {
+                panic!(
+                    "objects extension: object {} is configured twice",
+                    object.name
+                );
+            }
+        }
+        //everything announced before the restart is scheduled so the removed ones get killed
+        let announced = std::fs::read(&cfg.state_file)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<HashMap<String, Object>>(&json).ok())
+            .unwrap_or_default();
+        let ext = Self(Arc::new(Inner {
+            cfg: cfg.clone(),
+            own_writer: Mutex::new(None),
+            schedules: Mutex::new(HashMap::new()),
+            file_modified: Mutex::new(None),
+            file_objects: Mutex::new(vec![]),
+        }));
+        {
+            let mut schedules = ext.0.schedules.lock();
+            for (name, object) in announced {
+                schedules.insert(name, ext.schedule(object));
+            }
+        }
+        if ext.reload().is_none() {
+            panic!(
+                "objects extension failed to load {:?}",
+                cfg.objects_file.as_ref()
+            );
+        }
+        ext.sync();
+        ext
+    }
+    fn schedule(&self, object: Object) -> Schedule {
+        Schedule {
+            object,
+            live: true,
+            next: Instant::now(),
+            step: Duration::from_secs(self.0.cfg.initial_interval_secs.max(1)),
+            kills_left: self.0.cfg.kill_count,
+        }
+    }
+    /// reads the objects file again if it changed, a broken file keeps the previous objects
+    ///
+    /// none when the file is broken, otherwise whether other objects were loaded
+    fn reload(&self) -> Option<bool> {
+        let Some(path) = &self.0.cfg.objects_file else {
+            return Some(false);
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let previous = {
+            let mut file_modified = self.0.file_modified.lock();
+            if *file_modified == Some(modified) {
+                return Some(false);
+            }
+            //remember the broken version so the error is reported once per change
+            file_modified.replace(modified)
+        };
+        let parsed = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| toml::from_str::<ObjectsFile>(&s).map_err(|e| e.to_string()));
+        let mut objects = match parsed {
+            Ok(file) => file.objects,
+            Err(e) => {
+                self.error(&format!("failed to load {path:?}: {e}"));
+                return None;
+            }
+        };
+        objects.retain(|o| {
+            o.check()
+                .map_err(|e| self.error(&format!("skipping object in {path:?}: {e}")))
+                .is_ok()
+        });
+        if previous.is_some() {
+            self.log(&format!("reloaded {path:?}"));
+        }
+        let mut file_objects = self.0.file_objects.lock();
+        if *file_objects == objects {
+            return Some(false);
+        }
+        *file_objects = objects;
+        Some(true)
+    }
+    /// reschedules changed objects and kills the ones no longer configured
+    fn sync(&self) {
+        let mut wanted = self.0.cfg.objects.clone();
+        for object in self.0.file_objects.lock().iter() {
+            if wanted.iter().any(|o| o.name == object.name) {
+                self.warn(&format!("object {} is configured twice", object.name));
+            } else {
+                wanted.push(object.clone());
+            }
+        }
+        let mut changed = false;
+        let mut schedules = self.0.schedules.lock();
+        for object in &wanted {
+            match schedules.get(&object.name) {
+                Some(s) if s.live && &s.object == object => {}
+                _ => {
+                    schedules.insert(object.name.clone(), self.schedule(object.clone()));
+                    changed = true;
+                }
+            }
+        }
+        for (name, s) in schedules.iter_mut() {
+            if s.live && !wanted.iter().any(|o| &o.name == name) {
+                self.log(&format!("killing object {name}"));
+                *s = Schedule {
+                    live: false,
+                    ..self.schedule(s.object.clone())
+                };
+                changed = true;
+            }
+        }
+        if changed {
+            self.save(&schedules);
+        }
+    }
+    fn save(&self, schedules: &HashMap<String, Schedule>) {
+        let announced = schedules
+            .iter()
+            .map(|(name, s)| (name, &s.object))
+            .collect::<HashMap<_, _>>();
+        let path = &self.0.cfg.state_file;
+        if let Err(e) = serde_json::to_vec(&announced)
+            .map_err(|e| e.to_string())
+            .and_then(|json| std::fs::write(path, json).map_err(|e| e.to_string()))
+        {
+            self.error(&format!("failed to write {path:?}: {e}"));
+        }
+    }
+    fn packet(&self, object: &Object, live: bool) -> String {
+        let cfg = &self.0.cfg;
+        let source = if cfg.source.is_empty() {
+            &crate::Config::get().callsign
+        } else {
+            &cfg.source
+        };
+        let path = if cfg.path.is_empty() {
+            String::new()
+        } else {
+            format!(",{}", cfg.path)
+        };
+        let position = if object.compressed {
+            position::encode_compressed(
+                object.latitude,
+                object.longitude,
+                object.symbol_table,
+                object.symbol,
+                CompressedExt::None,
+            )
+        } else {
+            format!(
+                "{}{}{}{}",
+                position::encode_lat(object.latitude),
+                object.symbol_table,
+                position::encode_lon(object.longitude),
+                object.symbol
+            )
+        };
+        let data = if object.item {
+            let state = if live { '!' } else { '_' };
+            format!("){}{state}", object.name)
+        } else {
+            let state = if live { '*' } else { '_' };
+            format!(";{:<9}{state}{}", object.name, aprs_timestamp(now_unix()))
+        };
+        format!(
+            "{}>{}{path}:{data}{position}{}\n",
+            source.to_uppercase(),
+            cfg.destination.to_uppercase(),
+            object.comment_text()
+        )
+    }
+    /// sends the objects that are due and moves their schedules on
+    async fn transmit_due(&self) {
+        let Some(writer) = self.0.own_writer.lock().clone() else {
+            return;
+        };
+        let now = Instant::now();
+        let mut packets = vec![];
+        {
+            let mut schedules = self.0.schedules.lock();
+            let mut killed = vec![];
+            for (name, s) in schedules.iter_mut() {
+                if s.next > now {
+                    continue;
+                }
+                packets.push(self.packet(&s.object, s.live));
+                let interval = s.object.interval_mins.unwrap_or(self.0.cfg.interval_mins);
+                s.next = now + s.step;
+                s.step = (s.step * 2).min(Duration::from_secs(60 * interval));
+                if !s.live {
+                    s.kills_left -= 1;
+                    if s.kills_left == 0 {
+                        killed.push(name.clone());
+                    }
+                }
+            }
+            if !killed.is_empty() {
+                for name in killed {
+                    schedules.remove(&name);
+                }
+                self.save(&schedules);
+            }
+        }
+        for packet in packets {
+            if let Err(e) = writer.send(packet.into_bytes()).await {
+                self.error(&format!("failed to send object: {e}"));
+                return;
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for Objects {
+    fn name(&self) -> &'static str {
+        "objects"
+    }
+    async fn handle(&self, _: &str) -> Option<Vec<u8>> {
+        None
+    }
+    fn set_own_writer(&self, w: mpsc::Sender<Vec<u8>>) {
+        *self.0.own_writer.lock() = Some(w);
+    }
+    fn on_start(&self) {
+        let ext = self.clone();
+        let reload_every = Duration::from_secs(self.0.cfg.reload_check_secs.max(1));
+        tokio::spawn(async move {
+            let mut last_reload = Instant::now();
+            loop {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                if last_reload.elapsed() >= reload_every {
+                    last_reload = Instant::now();
+                    if ext.reload() == Some(true) {
+                        ext.sync();
+                    }
+                }
+                ext.transmit_due().await;
+            }
+        });
+    }
+    fn on_connected(&self, _server: &str, _verified: bool) {
+        //everything is announced again right after a (re)connect
+        let now = Instant::now();
+        for s in self.0.schedules.lock().values_mut() {
+            s.next = now;
+        }
+    }
+    fn on_disconnected(&self, _reason: &str) {
+        *self.0.own_writer.lock() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn objects() -> Objects {
+        Objects(Arc::new(Inner {
+            cfg: Config {
+                source: "n0call-1".into(),
+                ..Default::default()
+            },
+            own_writer: Mutex::new(None),
+            schedules: Mutex::new(HashMap::new()),
+            file_modified: Mutex::new(None),
+            file_objects: Mutex::new(vec![]),
+        }))
+    }
+
+    fn repeater() -> Object {
+        Object {
+            name: "W1AW-R".into(),
+            latitude: 49.058333,
+            longitude: -72.029167,
+            comment: "net at 8pm".into(),
+            frequency_mhz: Some(146.94),
+            tone_hz: Some(88.5),
+            offset_khz: Some(-600),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn comment_text_frequency_spec() {
+        let object = repeater();
+        assert_eq!(object.comment_text(), "146.940MHz T088 -060 net at 8pm");
+        let object = Object {
+            tone_squelch: true,
+            tone_hz: Some(100.0),
+            offset_khz: Some(5000),
+            comment: String::new(),
+            ..object
+        };
+        assert_eq!(object.comment_text(), "146.940MHz C100 +500");
+        let object = Object {
+            frequency_mhz: Some(53.5),
+            tone_hz: None,
+            dcs: Some(23),
+            offset_khz: None,
+            ..object
+        };
+        assert_eq!(object.comment_text(), "053.500MHz D023");
+        assert_eq!(Object::default().comment_text(), "");
+    }
+
+    #[test]
+    fn item_packets() {
+        let item = Object {
+            name: "AID #2".into(),
+            item: true,
+            symbol: 'A',
+            frequency_mhz: None,
+            tone_hz: None,
+            offset_khz: None,
+            comment: "first aid".into(),
+            ..repeater()
+        };
+        let ext = objects();
+        assert_eq!(
+            ext.packet(&item, true),
+            "N0CALL-1>AP4GNT,TCPIP*:)AID #2!4903.50N/07201.75WAfirst aid\n"
+        );
+        assert_eq!(
+            ext.packet(&item, false),
+            "N0CALL-1>AP4GNT,TCPIP*:)AID #2_4903.50N/07201.75WAfirst aid\n"
+        );
+    }
+
+    #[test]
+    fn object_packets() {
+        let ext = objects();
+        let packet = ext.packet(&repeater(), true);
+        let (head, position) = packet.split_at(packet.len() - 51);
+        assert!(head.starts_with("N0CALL-1>AP4GNT,TCPIP*:;W1AW-R   *"));
+        assert!(head.ends_with('z'));
+        assert_eq!(
+            head.len(),
+            "N0CALL-1>AP4GNT,TCPIP*:;W1AW-R   *DDHHMMz".len()
+        );
+        assert_eq!(
+            position,
+            "4903.50N/07201.75Wr146.940MHz T088 -060 net at 8pm\n"
+        );
+        let killed = ext.packet(&repeater(), false);
+        assert!(killed.starts_with("N0CALL-1>AP4GNT,TCPIP*:;W1AW-R   _"));
+        let compressed = Object {
+            compressed: true,
+            ..repeater()
+        };
+        let packet = ext.packet(&compressed, true);
+        assert!(packet.ends_with("r  C146.940MHz T088 -060 net at 8pm\n"));
+    }
+}
@@ -0,0 +1,145 @@
+use std::{collections::VecDeque, time::Duration};
+
+use educe::Educe;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::utils::glob_match;
+
+/// what user code (processes, scripts and wasm plugins) may send to aprs-is
+/// flattened into their configs, so the keys sit next to the other extension settings
+#[derive(Debug, Serialize, Deserialize, Clone, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct Policy {
+    /// source callsign patterns packets may use besides the agent callsign, `*` and `?` match
+    pub transmit_sources: Vec<String>,
+    /// packets that may be sent per minute, messages count once per part
+    #[educe(Default = 30)]
+    pub max_packets_per_min: usize,
+}
+
+impl Policy {
+    pub fn build(&self) -> Outbound {
+        Outbound {
+            policy: self.clone(),
+            sent: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+/// enforces a policy on the packets of one extension
+pub struct Outbound {
+    policy: Policy,
+    /// when the packets of the last minute were let through
+    sent: Mutex<VecDeque<Instant>>,
+}
+
+impl Outbound {
+    /// checks a raw packet, returns it with a single line ending or why it was refused
+    pub fn packet(&self, packet: &str) -> Result<String, String> {
+        let packet = packet.trim_end();
+        if packet.is_empty() || packet.contains(['\r', '\n']) {
+            return Err("refusing an empty packet or one spanning multiple lines".to_string());
+        }
+        let source = packet.split_once('>').map(|(s, _)| s).unwrap_or_default();
+        if !source.eq_ignore_ascii_case(&crate::Config::get().callsign)
+            && !self
+                .policy
+                .transmit_sources
+                .iter()
+                .any(|p| glob_match(p, source))
+        {
+            return Err(format!("refusing to transmit a packet from {source:?}"));
+        }
+        self.take(1)?;
+        Ok(format!("{packet}\n"))
+    }
+    /// builds the messages from the agent callsign carrying text, split to fit aprs messages
+    /// an id is only allowed if the text fits a single message
+    pub fn message(&self, to: &str, text: &str, id: Option<&str>) -> Result<Vec<String>, String> {
+        if to.is_empty() || to.len() > 9 || to.contains(['\r', '\n', ':']) {
+            return Err(format!("refusing to send a message to {to:?}"));
+        }
+        if text.contains(['\r', '\n']) {
+            return Err(format!(
+                "refusing to send a message to {to} spanning multiple lines"
+            ));
+        }
+        if id.is_some_and(|id| {
+            id.is_empty() || id.len() > 5 || !id.chars().all(|c| c.is_ascii_alphanumeric())
+        }) {
+            return Err(format!("refusing message id {id:?}"));
+        }
+        let parts = crate::aprs::split_message(text);
+        if parts.is_empty() {
+            return Err(format!("refusing to send an empty message to {to}"));
+        }
+        if id.is_some() && parts.len() > 1 {
+            return Err(format!(
+                "message to {to} with an id does not fit a single message"
+            ));
+        }
+        self.take(parts.len())?;
+        let callsign = &crate::Config::get().callsign;
+        Ok(parts
+            .iter()
+            .map(|part| crate::aprs::message_packet(callsign, to, part, id))
+            .collect())
+    }
+    /// counts n packets against the per minute limit if they all fit
+    fn take(&self, n: usize) -> Result<(), String> {
+        let now = Instant::now();
+        let mut sent = self.sent.lock();
+        while sent
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= Duration::from_secs(60))
+        {
+            sent.pop_front();
+        }
+        if sent.len() + n > self.policy.max_packets_per_min {
+            return Err("more than max_packets_per_min, dropping outbound packet".to_string());
+        }
+        for _ in 0..n {
+            sent.push_back(now);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_extra_lines() {
+        let outbound = Policy::default().build();
+        assert!(outbound.packet("N0CALL>APRS:>a\nN0CALL>APRS:>b").is_err());
+        assert!(outbound.packet("  \r\n").is_err());
+        assert!(outbound
+            .message("N0CALL", "hi\r\nN0CALL>APRS:>b", None)
+            .is_err());
+        assert!(outbound.message("N0CALL\n", "hi", None).is_err());
+        assert!(outbound.message("N0CALL-123", "hi", None).is_err());
+        assert!(outbound.message("", "hi", None).is_err());
+        assert!(outbound.message("N0CALL", "hi", Some("1}")).is_err());
+        assert!(outbound.message("N0CALL", "   ", None).is_err());
+        assert!(outbound
+            .message("N0CALL", &"word ".repeat(20), Some("1"))
+            .is_err());
+    }
+
+    #[test]
+    fn rate() {
+        let outbound = Policy {
+            max_packets_per_min: 3,
+            ..Default::default()
+        }
+        .build();
+        assert!(outbound.take(2).is_ok());
+        assert!(outbound.take(2).is_err());
+        assert!(outbound.take(1).is_ok());
+        assert!(outbound.take(1).is_err());
+    }
+}
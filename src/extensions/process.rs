@@ -0,0 +1,305 @@
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use educe::Educe;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt},
+    sync::{mpsc, watch},
+    time::Instant,
+};
+
+use super::{
+    filter::Filter,
+    outbound::{Outbound, Policy},
+    Extension,
+};
+use crate::aprs::packet::Packet;
+
+/// an external program supervised by the agent
+/// it gets every matching packet as a json line on stdin and can write json commands to stdout:
+/// `{"cmd":"transmit","packet":"N0CALL>APRS:>hello"}`
+/// `{"cmd":"send_message","to":"N0CALL-7","text":"hi","id":"1"}`
+/// `{"cmd":"log","level":"warn","message":"something happened"}`
+#[derive(Debug, Serialize, Deserialize, Clone, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct Config {
+    #[educe(Default = true)]
+    pub enabled: bool,
+    /// the extension shows up as `process:<name>` in logs and queue settings
+    pub name: String,
+    /// program followed by its arguments
+    pub command: Vec<String>,
+    pub env: HashMap<String, String>,
+    #[educe(Default = 5)]
+    pub restart_delay_secs: u64,
+    /// packets buffered while the process is busy or restarting, more are dropped
+    #[educe(Default = 256)]
+    pub buffer: usize,
+    /// sources and rate of what the process sends with `transmit` and `send_message`
+    #[serde(flatten)]
+    pub outbound: Policy,
+    pub filter: Filter,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Command {
+    Transmit {
+        packet: String,
+    },
+    SendMessage {
+        to: String,
+        text: String,
+        #[serde(default)]
+        id: Option<String>,
+    },
+    Log {
+        #[serde(default)]
+        level: LogLevel,
+        message: String,
+    },
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum LogLevel {
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(Clone)]
+pub struct ProcessExtension(Arc<Inner>);
+struct Inner {
+    name: &'static str,
+    cfg: Config,
+    packets: Mutex<Option<mpsc::Sender<String>>>,
+    packets_rx: Mutex<Option<mpsc::Receiver<String>>>,
+    own_writer: Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+    /// set on shutdown so a restart backoff does not outlive it
+    shutdown: watch::Sender<bool>,
+    stopped: watch::Sender<bool>,
+    /// packets that never reached the process
+    dropped: AtomicU64,
+    outbound: Outbound,
+}
+
+impl ProcessExtension {
+    pub fn new(cfg: &Config) -> Self {
+        if cfg.name.is_empty() {
+            panic!("process extension requires a name");
+        }
+        if cfg.command.is_empty() {
+            panic!("process extension {} requires a command", cfg.name);
+        }
+        let (tx, rx) = mpsc::channel(cfg.buffer.max(1));
+        Self(Arc::new(Inner {
+            //registered once at startup, leaking gives the &'static name the trait asks for
+            name: Box::leak(format!("process:{}", cfg.name).into_boxed_str()),
+            cfg: cfg.clone(),
+            packets: Mutex::new(Some(tx)),
+            packets_rx: Mutex::new(Some(rx)),
+            own_writer: Mutex::new(None),
+            shutdown: watch::channel(false).0,
+            stopped: watch::channel(false).0,
+            dropped: AtomicU64::new(0),
+            outbound: cfg.outbound.build(),
+        }))
+    }
+    fn spawn_child(&self) -> std::io::Result<tokio::process::Child> {
+        let cmd = &self.0.cfg.command;
+        tokio::process::Command::new(&cmd[0])
+            .args(&cmd[1..])
+            .envs(&self.0.cfg.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+    }
+    fn drop_packet(&self, reason: &str) {
+        let dropped = self.0.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        self.warn(&format!(
+            "{reason}, dropping packet ({dropped} dropped so far)"
+        ));
+    }
+    /// waits before the next start, returns false if shutdown began in the meantime
+    async fn backoff(&self, delay: Duration) -> bool {
+        let mut shutdown = self.0.shutdown.subscribe();
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => true,
+            _ = shutdown.wait_for(|s| *s) => false,
+        }
+    }
+    /// keeps the process running until the packet channel is closed on shutdown
+    async fn supervise(&self, mut packets: mpsc::Receiver<String>) {
+        let restart_delay = Duration::from_secs(self.0.cfg.restart_delay_secs);
+        loop {
+            let mut child = match self.spawn_child() {
+                Ok(child) => child,
+                Err(e) => {
+                    self.error(&format!("failed to start {:?}: {e}", self.0.cfg.command));
+                    if !self.backoff(restart_delay).await {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            self.log(&format!(
+                "started with pid {}",
+                child.id().unwrap_or_default()
+            ));
+            let mut stdin = child.stdin.take();
+            let stdout = child.stdout.take().expect("stdout is piped");
+            let mut lines = tokio::io::BufReader::new(stdout).lines();
+            let mut input_closed = false;
+            loop {
+                tokio::select! {
+                    packet = packets.recv(), if !input_closed => {
+                        match (packet, stdin.as_mut()) {
+                            (Some(packet), Some(w)) => {
+                                if let Err(e) = w.write_all(packet.as_bytes()).await {
+                                    self.error(&format!("failed to write to stdin: {e}"));
+                                    self.drop_packet("stdin is closed");
+                                    stdin = None;
+                                }
+                            }
+                            (Some(_), None) => self.drop_packet("stdin is closed"),
+                            (None, _) => {
+                                //shutting down, eof on stdin lets the process finish and exit
+                                input_closed = true;
+                                stdin = None;
+                            }
+                        }
+                    }
+                    line = lines.next_line() => {
+                        match line {
+                            Ok(Some(line)) => self.run(&line).await,
+                            Ok(None) => break,
+                            Err(e) => {
+                                self.error(&format!("failed to read stdout: {e}"));
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            drop(stdin);
+            match child.wait().await {
+                Ok(status) => self.warn(&format!("exited with {status}")),
+                Err(e) => self.error(&format!("failed to wait for exit: {e}")),
+            }
+            if input_closed || !self.backoff(restart_delay).await {
+                break;
+            }
+        }
+        let dropped = packets.len();
+        if dropped > 0 {
+            self.warn(&format!(
+                "{dropped} buffered packets not delivered on shutdown"
+            ));
+        }
+        self.0.stopped.send_replace(true);
+    }
+    async fn run(&self, line: &str) {
+        let cmd = match serde_json::from_str::<Command>(line) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                self.error(&format!("invalid command {line:?}: {e}"));
+                return;
+            }
+        };
+        let packets = match cmd {
+            Command::Log { level, message } => {
+                match level {
+                    LogLevel::Info => self.log(&message),
+                    LogLevel::Warn => self.warn(&message),
+                    LogLevel::Error => self.error(&message),
+                }
+                return;
+            }
+            Command::Transmit { packet } => self.0.outbound.packet(&packet).map(|p| vec![p]),
+            Command::SendMessage { to, text, id } => {
+                self.0.outbound.message(&to, &text, id.as_deref())
+            }
+        };
+        let packets = match packets {
+            Ok(packets) => packets,
+            Err(e) => {
+                self.error(&e);
+                return;
+            }
+        };
+        let Some(writer) = self.0.own_writer.lock().clone() else {
+            self.warn("not connected, dropping outbound packet");
+            return;
+        };
+        for packet in packets {
+            if writer.send(packet.into_bytes()).await.is_err() {
+                self.warn("connection closed, dropping outbound packet");
+                return;
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for ProcessExtension {
+    fn name(&self) -> &'static str {
+        self.0.name
+    }
+    fn is_spawnable(&self) -> bool {
+        true
+    }
+    async fn handle(&self, line: &str) -> Option<Vec<u8>> {
+        let packet = Packet::parse(line)?;
+        let mut json = serde_json::to_string(&packet)
+            .map_err(|e| self.error(&format!("failed to encode packet: {e}")))
+            .ok()?;
+        json.push('\n');
+        let tx = self.0.packets.lock().clone()?;
+        if tx.try_send(json).is_err() {
+            self.drop_packet("process is not keeping up");
+        }
+        None
+    }
+    fn set_own_writer(&self, w: mpsc::Sender<Vec<u8>>) {
+        *self.0.own_writer.lock() = Some(w);
+    }
+    fn on_start(&self) {
+        let Some(packets) = self.0.packets_rx.lock().take() else {
+            return;
+        };
+        let ext = self.clone();
+        tokio::spawn(async move { ext.supervise(packets).await });
+    }
+    fn on_disconnected(&self, _reason: &str) {
+        *self.0.own_writer.lock() = None;
+    }
+    async fn on_shutdown(&self, deadline: Instant) {
+        self.0.packets.lock().take();
+        self.0.shutdown.send_replace(true);
+        let mut stopped = self.0.stopped.subscribe();
+        let wait = async {
+            while !*stopped.borrow_and_update() {
+                if stopped.changed().await.is_err() {
+                    return;
+                }
+            }
+        };
+        if tokio::time::timeout_at(deadline, wait).await.is_err() {
+            self.warn("process did not exit before the shutdown deadline");
+        }
+    }
+}
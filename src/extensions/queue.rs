@@ -0,0 +1,175 @@
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use educe::Educe;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// the oldest queued line is discarded to make room
+    #[default]
+    DropOldest,
+    /// the incoming line is discarded
+    DropNewest,
+    /// the upstream read loop waits until there is room
+    Block,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct QueueSettings {
+    #[educe(Default = 1024)]
+    pub capacity: usize,
+    #[educe(Default = 2)]
+    pub workers: usize,
+    pub overflow: OverflowPolicy,
+}
+
+/// bounded queue feeding the worker pool of a spawnable extension or an extension's own workers
+pub struct WorkQueue<T = String> {
+    items: Mutex<VecDeque<T>>,
+    settings: QueueSettings,
+    has_items: Notify,
+    has_space: Notify,
+    /// queued plus currently processed items
+    pending: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl<T> WorkQueue<T> {
+    pub fn new(settings: QueueSettings) -> Self {
+        Self {
+            items: Mutex::new(VecDeque::with_capacity(settings.capacity)),
+            settings: QueueSettings {
+                capacity: settings.capacity.max(1),
+                workers: settings.workers.max(1),
+                ..settings
+            },
+            has_items: Notify::new(),
+            has_space: Notify::new(),
+            pending: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+    pub fn workers(&self) -> usize {
+        self.settings.workers
+    }
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+    /// queues an item according to the overflow policy, returns the item dropped to make room
+    pub async fn push(&self, item: T) -> Option<T> {
+        loop {
+            let space = self.has_space.notified();
+            {
+                let mut items = self.items.lock();
+                if items.len() < self.settings.capacity {
+                    items.push_back(item);
+                    self.pending.fetch_add(1, Ordering::SeqCst);
+                    self.has_items.notify_one();
+                    return None;
+                }
+                match self.settings.overflow {
+                    OverflowPolicy::DropNewest => {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        return Some(item);
+                    }
+                    OverflowPolicy::DropOldest => {
+                        let oldest = items.pop_front();
+                        items.push_back(item);
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        self.has_items.notify_one();
+                        return oldest;
+                    }
+                    OverflowPolicy::Block => {}
+                }
+            }
+            space.await;
+        }
+    }
+    /// waits for the next item, it stays pending until the returned guard is dropped
+    pub async fn pop(&self) -> (T, Processing<'_, T>) {
+        loop {
+            let items = self.has_items.notified();
+            if let Some(item) = self.items.lock().pop_front() {
+                self.has_space.notify_one();
+                return (item, Processing(self));
+            }
+            items.await;
+        }
+    }
+}
+
+/// marks a popped item as done when dropped, also when its worker panics or is cancelled
+pub struct Processing<'a, T>(&'a WorkQueue<T>);
+impl<T> Drop for Processing<'_, T> {
+    fn drop(&mut self) {
+        self.0.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    async fn full(overflow: OverflowPolicy) -> WorkQueue<u32> {
+        let queue = WorkQueue::new(QueueSettings {
+            capacity: 2,
+            workers: 1,
+            overflow,
+        });
+        assert_eq!(queue.push(1).await, None);
+        assert_eq!(queue.push(2).await, None);
+        queue
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let queue = full(OverflowPolicy::DropOldest).await;
+        assert_eq!(queue.push(3).await, Some(1));
+        assert_eq!(queue.push(4).await, Some(2));
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queue.pending(), 2);
+        assert_eq!(queue.pop().await.0, 3);
+        assert_eq!(queue.pop().await.0, 4);
+    }
+
+    #[tokio::test]
+    async fn drop_newest() {
+        let queue = full(OverflowPolicy::DropNewest).await;
+        assert_eq!(queue.push(3).await, Some(3));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.pending(), 2);
+        assert_eq!(queue.pop().await.0, 1);
+        assert_eq!(queue.pop().await.0, 2);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let queue = full(OverflowPolicy::Block).await;
+        let push = queue.push(3);
+        tokio::pin!(push);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut push)
+            .await
+            .is_err());
+        let (first, processing) = queue.pop().await;
+        assert_eq!(first, 1);
+        assert_eq!(push.await, None);
+        assert_eq!(queue.dropped(), 0);
+        //the popped item stays pending until its guard is dropped
+        assert_eq!(queue.pending(), 3);
+        drop(processing);
+        assert_eq!(queue.pending(), 2);
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use educe::Educe;
+use parking_lot::Mutex;
+use rhai::{Dynamic, Engine, Scope, AST};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    filter::Filter,
+    outbound::{Outbound, Policy},
+    Extension,
+};
+use crate::aprs::packet::Packet;
+
+/// a rhai script defining `fn handle(packet)`, the packet is a map with the fields of `aprs::packet::Packet`
+/// scripts can call `reply(text)`, `send_message(to, text)`, `transmit(packet)`, `log(msg)`,
+/// `store_get(key)` and `store_set(key, value)`, the file is reloaded whenever it changes
+/// long message texts are split, packets breaking the outbound policy are dropped and logged
+#[derive(Debug, Serialize, Deserialize, Clone, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct Config {
+    #[educe(Default = true)]
+    pub enabled: bool,
+    /// the extension shows up as `script:<name>` in logs
+    pub name: String,
+    pub path: PathBuf,
+    #[educe(Default = 2)]
+    pub reload_check_secs: u64,
+    /// upper bound of rhai operations per handle call so a runaway loop cannot hang the agent
+    #[educe(Default = 100_000)]
+    pub max_operations: u64,
+    /// sources and rate of what the script sends
+    #[serde(flatten)]
+    pub outbound: Policy,
+    pub filter: Filter,
+}
+
+/// packets queued by the script functions during a single handle call
+#[derive(Default)]
+struct Outbox {
+    reply_to: String,
+    packets: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct ScriptExtension(Arc<Inner>);
+struct Inner {
+    name: &'static str,
+    cfg: Config,
+    engine: Engine,
+    script: Mutex<Option<(AST, Option<SystemTime>)>>,
+    outbox: Arc<Mutex<Outbox>>,
+    /// handle calls share the outbox so they run one at a time
+    call: Mutex<()>,
+}
+
+impl ScriptExtension {
+    pub fn new(cfg: &Config) -> Self {
+        if cfg.name.is_empty() {
+            panic!("script extension requires a name");
+        }
+        let name: &'static str = Box::leak(format!("script:{}", cfg.name).into_boxed_str());
+        let outbox = Arc::new(Mutex::new(Outbox::default()));
+        let engine = Self::engine(name, cfg, outbox.clone(), Arc::new(cfg.outbound.build()));
+        let ext = Self(Arc::new(Inner {
+            name,
+            cfg: cfg.clone(),
+            engine,
+            script: Mutex::new(None),
+            outbox,
+            call: Mutex::new(()),
+        }));
+        if !ext.reload() {
+            panic!(
+                "script extension {} failed to load {:?}",
+                cfg.name, cfg.path
+            );
+        }
+        ext
+    }
+    fn engine(
+        name: &'static str,
+        cfg: &Config,
+        outbox: Arc<Mutex<Outbox>>,
+        outbound: Arc<Outbound>,
+    ) -> Engine {
+        let mut engine = Engine::new();
+        engine.set_max_operations(cfg.max_operations);
+        engine.on_print(move |msg| eprintln!("\x1B[32m{name}:\x1B[0m {msg}"));
+        engine.register_fn("log", move |msg: &str| {
+            eprintln!("\x1B[32m{name}:\x1B[0m {msg}")
+        });
+        let store = Arc::new(Mutex::new(HashMap::<String, Dynamic>::new()));
+        let s = store.clone();
+        engine.register_fn("store_get", move |key: &str| {
+            s.lock().get(key).cloned().unwrap_or(Dynamic::UNIT)
+        });
+        engine.register_fn("store_set", move |key: &str, value: Dynamic| {
+            store.lock().insert(key.to_string(), value);
+        });
+        let queue =
+            move |outbox: &Mutex<Outbox>, packets: Result<Vec<String>, String>| match packets {
+                Ok(packets) => outbox.lock().packets.extend(packets),
+                Err(e) => eprintln!("\x1B[31m{name}:\x1B[0m {e}"),
+            };
+        let (o, out) = (outbox.clone(), outbound.clone());
+        engine.register_fn("reply", move |text: &str| {
+            let to = o.lock().reply_to.clone();
+            queue(&o, out.message(&to, text, None));
+        });
+        let (o, out) = (outbox.clone(), outbound.clone());
+        engine.register_fn("send_message", move |to: &str, text: &str| {
+            queue(&o, out.message(to, text, None));
+        });
+        engine.register_fn("transmit", move |packet: &str| {
+            queue(&outbox, outbound.packet(packet).map(|p| vec![p]));
+        });
+        engine
+    }
+    /// compiles the script again if the file changed, a broken script keeps the previous version running
+    fn reload(&self) -> bool {
+        let path = &self.0.cfg.path;
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        {
+            let script = self.0.script.lock();
+            if matches!(*script, Some((_, m)) if m == modified) {
+                return true;
+            }
+        }
+        match self.0.engine.compile_file(path.clone()) {
+            Ok(ast) => {
+                if self.0.script.lock().replace((ast, modified)).is_some() {
+                    self.log(&format!("reloaded {path:?}"));
+                }
+                true
+            }
+            Err(e) => {
+                self.error(&format!("failed to compile {path:?}: {e}"));
+                //remember the broken version so the error is reported once per change
+                if let Some((_, m)) = self.0.script.lock().as_mut() {
+                    *m = modified;
+                }
+                false
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for ScriptExtension {
+    fn name(&self) -> &'static str {
+        self.0.name
+    }
+    async fn handle(&self, line: &str) -> Option<Vec<u8>> {
+        let packet = Packet::parse(line)?;
+        let ast = self.0.script.lock().as_ref()?.0.clone();
+        let arg = rhai::serde::to_dynamic(&packet)
+            .map_err(|e| self.error(&format!("failed to convert packet: {e}")))
+            .ok()?;
+        let packets = {
+            let _call = self.0.call.lock();
+            let mut outbox = self.0.outbox.lock();
+            outbox.reply_to = packet.from.clone();
+            outbox.packets.clear();
+            drop(outbox);
+            if let Err(e) =
+                self.0
+                    .engine
+                    .call_fn::<Dynamic>(&mut Scope::new(), &ast, "handle", (arg,))
+            {
+                self.error(&format!("handle failed: {e}"));
+            }
+            std::mem::take(&mut self.0.outbox.lock().packets)
+        };
+        if packets.is_empty() {
+            None
+        } else {
+            Some(packets.concat().into_bytes())
+        }
+    }
+    fn on_start(&self) {
+        let ext = self.clone();
+        let every = Duration::from_secs(self.0.cfg.reload_check_secs.max(1));
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(every).await;
+                ext.reload();
+            }
+        });
+    }
+}
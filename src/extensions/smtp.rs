@@ -1,37 +1,439 @@
-use aprs_parser::AprsData;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
 use educe::Educe;
-use lettre::{transport::smtp::authentication::Credentials, Transport};
+use lettre::{
+    message::MultiPart,
+    transport::smtp::{
+        authentication::{Credentials, Mechanism},
+        client::{Tls, TlsParameters},
+        extension::ClientId,
+    },
+    AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use tap::{Pipe, TapOptional};
+use tokio::{sync::mpsc, time::Instant};
+
+use super::{filter::Filter, Extension};
+use crate::{
+    aprs::{
+        messaging::Messenger,
+        packet::Packet,
+        stations::{last_position, Position},
+    },
+    utils::{format_utc, now_unix, render_template},
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AckPolicy {
+    /// ack as soon as the email is in the outbox, failures are reported with a message
+    OnReceipt,
+    /// ack once the smtp server accepted the email, a permanent failure is answered with a rej
+    #[default]
+    OnDelivery,
+}
 
-use super::Extension;
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Security {
+    /// tls from the first byte, usually port 465
+    #[default]
+    Tls,
+    /// plain connection upgraded with starttls, usually port 587, fails if the server does not offer it
+    Starttls,
+    /// no encryption at all, only for local relays and test servers
+    Plain,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMechanism {
+    Plain,
+    Login,
+    Xoauth2,
+}
+impl From<AuthMechanism> for Mechanism {
+    fn from(m: AuthMechanism) -> Self {
+        match m {
+            AuthMechanism::Plain => Mechanism::Plain,
+            AuthMechanism::Login => Mechanism::Login,
+            AuthMechanism::Xoauth2 => Mechanism::Xoauth2,
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone, Educe)]
 #[educe(Default)]
+#[serde(default)]
 pub struct Config {
     pub enabled: bool,
     #[educe(Default = "smtp.example.com:25")]
     pub smtp_server: String,
+    pub security: Security,
+    /// authentication is skipped when the username is empty
     #[educe(Default = "smtp@example.com")]
     pub smtp_username: String,
     #[educe(Default = "smtp_password")]
     pub smtp_password: String,
+    /// mechanisms tried in order, plain and login when empty
+    pub auth_mechanisms: Vec<AuthMechanism>,
+    /// name sent with ehlo, the local hostname when unset
+    pub helo_name: Option<String>,
     #[educe(Default(expression = r#"vec!["N0CALL"].iter().map(ToString::to_string).collect()"#))]
     pub allowed_senders: Vec<String>,
     #[educe(Default(expression = r#"vec!["EMAIL"].iter().map(ToString::to_string).collect()"#))]
     pub allowed_recipients: Vec<String>,
+    /// gates addresses typed in full, addresses from the address books are always allowed
     pub allowed_receiver_emails: Vec<String>,
+    /// per sender aliases like `HOME` usable instead of an address, keyed by callsign with or
+    /// without ssid, the `*` book is shared by all senders
+    pub address_books: HashMap<String, HashMap<String, String>>,
     #[educe(Default = "https://github.com/ta3pks/aprs-agent <aprs@nodomain.com>")]
     pub from_email: String,
+    /// subject, body and html templates can use `{from}`, `{addressee}`, `{path}`, `{message_id}`,
+    /// `{text}`, `{received_at}` (unix time), `{received_time}`, and the last known position of the
+    /// sender as `{latitude}`, `{longitude}`, `{map_link}` and `{position}`, empty when unknown
+    #[educe(Default = "This email was sent using https://github.com/ta3pks/aprs-agent")]
+    pub subject_template: String,
+    #[educe(Default = "{text}")]
+    pub body_template: String,
+    /// adds an html alternative part, variables are html escaped
+    pub html_template: Option<String>,
+    /// emails wait here until the smtp server accepted them so they survive restarts
+    #[educe(Default = "smtp-outbox")]
+    pub outbox_dir: PathBuf,
+    pub ack_policy: AckPolicy,
+    #[educe(Default = 30)]
+    pub timeout_secs: u64,
+    #[educe(Default = 8)]
+    pub max_retries: u32,
+    #[educe(Default = 10)]
+    pub initial_backoff_secs: u64,
+    #[educe(Default = 900)]
+    pub max_backoff_secs: u64,
+    pub filter: Filter,
+}
+
+/// transport for the configured smtp relay, also used by other extensions that send email
+pub fn mailer(cfg: &Config) -> Result<AsyncSmtpTransport<Tokio1Executor>, String> {
+    let (host, port) = cfg.smtp_server.split_once(':').ok_or_else(|| {
+        format!(
+            "invalid smtp server: {} format must be host:port",
+            cfg.smtp_server
+        )
+    })?;
+    let tls =
+        || TlsParameters::new(host.to_string()).map_err(|e| format!("invalid tls settings: {}", e));
+    let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        .port(
+            port.parse()
+                .map_err(|e| format!("invalid smtp port: {}", e))?,
+        )
+        .tls(match cfg.security {
+            Security::Tls => Tls::Wrapper(tls()?),
+            Security::Starttls => Tls::Required(tls()?),
+            Security::Plain => Tls::None,
+        })
+        .timeout(Some(Duration::from_secs(cfg.timeout_secs)));
+    if !cfg.smtp_username.is_empty() {
+        builder = builder.credentials(Credentials::new(
+            cfg.smtp_username.clone(),
+            cfg.smtp_password.clone(),
+        ));
+    }
+    if !cfg.auth_mechanisms.is_empty() {
+        builder = builder.authentication(cfg.auth_mechanisms.iter().map(|&m| m.into()).collect());
+    }
+    if let Some(ref name) = cfg.helo_name {
+        builder = builder.hello_name(ClientId::Domain(name.clone()));
+    }
+    Ok(builder.build())
+}
+
+/// an email waiting in the outbox, serialized into the outbox dir
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Outgoing {
+    to_email: String,
+    #[serde(default)]
+    subject: String,
+    body: String,
+    #[serde(default)]
+    html: Option<String>,
+    /// the message the email was made of, needed to ack or reject it
+    packet: String,
+    /// failed attempts so far and the unix time of the next one
+    #[serde(default)]
+    attempts: u32,
+    #[serde(default)]
+    next_attempt: u64,
+    #[serde(skip)]
+    file: Option<PathBuf>,
+}
+
+#[derive(Clone)]
+pub struct SmtpEmailer(Arc<Inner>);
+struct Inner {
+    cfg: Config,
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    messenger: Messenger,
+    tx: mpsc::UnboundedSender<Outgoing>,
+    rx: Mutex<Option<mpsc::UnboundedReceiver<Outgoing>>>,
+    /// sender and message id of emails in the outbox, retransmits of them are ignored
+    queued: Mutex<HashSet<(String, String)>>,
+    pending: AtomicUsize,
+}
+
+/// value of a template variable for an email made of `packet` with `text` as its content
+fn template_var(packet: &Packet, text: &str, name: &str) -> Option<String> {
+    let position = || last_position(&packet.from);
+    Some(match name {
+        "text" => text.to_string(),
+        "path" => packet.path.join(","),
+        "received_time" => format_utc(packet.received_at),
+        "latitude" => position()
+            .map(|p| format!("{:.5}", p.latitude))
+            .unwrap_or_default(),
+        "longitude" => position()
+            .map(|p| format!("{:.5}", p.longitude))
+            .unwrap_or_default(),
+        "map_link" => position().map(|p| map_link(&p)).unwrap_or_default(),
+        "position" => position()
+            .map(|p| {
+                format!(
+                    "last position {:.5},{:.5} heard {} {}",
+                    p.latitude,
+                    p.longitude,
+                    format_utc(p.heard_at),
+                    map_link(&p)
+                )
+            })
+            .unwrap_or_default(),
+        _ => packet.var(name)?,
+    })
+}
+
+fn map_link(p: &Position) -> String {
+    format!(
+        "https://www.openstreetmap.org/?mlat={lat:.5}&mlon={lon:.5}#map=14/{lat:.5}/{lon:.5}",
+        lat = p.latitude,
+        lon = p.longitude
+    )
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn queue_key(packet: &Packet) -> Option<(String, String)> {
+    Some((packet.from.to_uppercase(), packet.message_id.clone()?))
 }
 
-pub struct SmtpEmailer(());
 impl SmtpEmailer {
     pub fn new(cfg: &Config) -> Self {
         if cfg.allowed_senders.is_empty() || cfg.allowed_recipients.is_empty() {
             panic!("smtp extension requires at least one allowed sender and recipient");
         }
-        Self(())
+        let mailer = mailer(cfg).unwrap_or_else(|e| panic!("smtp extension: {e}"));
+        std::fs::create_dir_all(&cfg.outbox_dir).expect("failed to create smtp outbox dir");
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self(Arc::new(Inner {
+            cfg: cfg.clone(),
+            mailer,
+            messenger: Messenger::default(),
+            tx,
+            rx: Mutex::new(Some(rx)),
+            queued: Mutex::new(HashSet::new()),
+            pending: AtomicUsize::new(0),
+        }))
+    }
+    /// looks the alias up in the book of the exact ssid, then the callsign, then the shared one
+    fn resolve_alias(&self, sender: &str, alias: &str) -> Option<String> {
+        let books = &self.0.cfg.address_books;
+        let callsign = sender.split('-').next().unwrap_or_default();
+        [sender, callsign, "*"].iter().find_map(|owner| {
+            let (_, book) = books.iter().find(|(k, _)| k.eq_ignore_ascii_case(owner))?;
+            book.iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(alias))
+                .map(|(_, email)| email.clone())
+        })
+    }
+    fn enqueue(&self, mut outgoing: Outgoing) {
+        let file = self.0.cfg.outbox_dir.join(format!(
+            "{}.json",
+            std::time::UNIX_EPOCH
+                .elapsed()
+                .unwrap_or_default()
+                .as_nanos()
+        ));
+        match serde_json::to_vec(&outgoing).map(|json| std::fs::write(&file, json)) {
+            Ok(Ok(())) => outgoing.file = Some(file),
+            _ => self.warn("failed to persist email, keeping it in memory only"),
+        }
+        self.0.pending.fetch_add(1, Ordering::SeqCst);
+        self.0.tx.send(outgoing).ok();
+    }
+    /// emails left in the outbox by a previous run
+    fn load_outbox(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.0.cfg.outbox_dir) else {
+            return;
+        };
+        let mut files = entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|e| e == "json"))
+            .collect::<Vec<_>>();
+        files.sort();
+        for file in files {
+            let outgoing = std::fs::read(&file)
+                .map_err(|e| e.to_string())
+                .and_then(|b| serde_json::from_slice::<Outgoing>(&b).map_err(|e| e.to_string()));
+            let outgoing = match outgoing {
+                Ok(outgoing) => outgoing,
+                Err(e) => {
+                    self.error(&format!("skipping outbox file {file:?}: {e}"));
+                    continue;
+                }
+            };
+            if let Some(key) = Packet::parse(&outgoing.packet).and_then(|p| queue_key(&p)) {
+                self.0.queued.lock().insert(key);
+            }
+            self.0.pending.fetch_add(1, Ordering::SeqCst);
+            self.0
+                .tx
+                .send(Outgoing {
+                    file: Some(file),
+                    ..outgoing
+                })
+                .ok();
+        }
+    }
+    /// sends emails as they become due, one backing off does not hold up the others
+    async fn run(&self, mut rx: mpsc::UnboundedReceiver<Outgoing>) {
+        let mut waiting = Vec::<Outgoing>::new();
+        loop {
+            let now = now_unix();
+            let (due, later) = std::mem::take(&mut waiting)
+                .into_iter()
+                .partition::<Vec<_>, _>(|o| o.next_attempt <= now);
+            waiting = later;
+            for outgoing in due {
+                waiting.extend(self.attempt(outgoing).await);
+            }
+            let next = waiting.iter().map(|o| o.next_attempt).min();
+            let wait = Duration::from_secs(next.unwrap_or_default().saturating_sub(now_unix()));
+            tokio::select! {
+                outgoing = rx.recv() => match outgoing {
+                    Some(outgoing) => waiting.push(outgoing),
+                    None => return,
+                },
+                _ = tokio::time::sleep(wait), if next.is_some() => {}
+            }
+        }
+    }
+    /// tries to send the email once, returns it again if it is to be retried later
+    async fn attempt(&self, mut outgoing: Outgoing) -> Option<Outgoing> {
+        let cfg = &self.0.cfg;
+        let delivered = match self.send(&outgoing).await {
+            Ok(()) => true,
+            Err((e, transient)) if transient && outgoing.attempts < cfg.max_retries => {
+                let backoff = cfg
+                    .initial_backoff_secs
+                    .saturating_mul(1 << outgoing.attempts.min(20))
+                    .min(cfg.max_backoff_secs);
+                self.warn(&format!(
+                    "email to {} failed, retrying in {backoff}s: {e}",
+                    outgoing.to_email
+                ));
+                outgoing.attempts += 1;
+                outgoing.next_attempt = now_unix() + backoff;
+                //the retry state survives a restart too
+                if let Some(ref file) = outgoing.file {
+                    if let Ok(json) = serde_json::to_vec(&outgoing) {
+                        std::fs::write(file, json).ok();
+                    }
+                }
+                return Some(outgoing);
+            }
+            Err((e, _)) => {
+                self.error(&format!(
+                    "email to {} failed, giving up: {e}",
+                    outgoing.to_email
+                ));
+                false
+            }
+        };
+        self.finish(&outgoing, delivered).await;
+        None
+    }
+    /// removes the email from the outbox and acks or rejects its message
+    async fn finish(&self, outgoing: &Outgoing, delivered: bool) {
+        if let Some(ref file) = outgoing.file {
+            std::fs::remove_file(file).ok();
+        }
+        self.0.pending.fetch_sub(1, Ordering::SeqCst);
+        let cfg = &self.0.cfg;
+        let Some(packet) = Packet::parse(&outgoing.packet) else {
+            return;
+        };
+        if let Some(key) = queue_key(&packet) {
+            self.0.queued.lock().remove(&key);
+        }
+        match (cfg.ack_policy, delivered) {
+            (AckPolicy::OnDelivery, true) => {
+                self.0.messenger.mark_handled(&packet);
+                self.0.messenger.ack(&packet).await;
+            }
+            (AckPolicy::OnDelivery, false) => self.0.messenger.reject(&packet).await,
+            (AckPolicy::OnReceipt, true) => {}
+            (AckPolicy::OnReceipt, false) => {
+                let from = packet.addressee.as_deref().unwrap_or_default();
+                let text = format!("email to {} failed", outgoing.to_email);
+                let msg = crate::aprs::message_packet(from, &packet.from, &text, None);
+                self.0.messenger.transmit(msg).await;
+            }
+        }
+    }
+    /// sends the email once, the error tells whether it is worth trying again
+    async fn send(&self, outgoing: &Outgoing) -> Result<(), (String, bool)> {
+        let message = lettre::Message::builder()
+            .from(
+                self.0
+                    .cfg
+                    .from_email
+                    .parse()
+                    .map_err(|e| (format!("invalid from email: {}", e), false))?,
+            )
+            .to(outgoing
+                .to_email
+                .parse()
+                .map_err(|e| (format!("invalid receiver email: {}", e), false))?)
+            .date_now()
+            .subject(outgoing.subject.clone());
+        let message = match outgoing.html {
+            Some(ref html) => message.multipart(MultiPart::alternative_plain_html(
+                outgoing.body.clone(),
+                html.clone(),
+            )),
+            None => message.body(outgoing.body.clone()),
+        }
+        .map_err(|e| (format!("failed to build email: {}", e), false))?;
+        self.0
+            .mailer
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| (e.to_string(), !e.is_permanent()))
     }
 }
 #[async_trait::async_trait]
@@ -41,113 +443,111 @@ impl Extension for SmtpEmailer {
     }
 
     async fn handle(&self, line: &str) -> Option<Vec<u8>> {
-        let package = aprs_parser::AprsPacket::decode_textual(line.as_bytes()).ok()?;
-        if package.data.data_type_identifier() != b':' {
+        let packet = Packet::parse(line)?;
+        if packet.kind() != "message" {
             return None;
         }
-        let to = package.to()?.clone();
-        let sender = package.from;
-        let cfg = &crate::Config::get().extensions.smtp;
+        let cfg = &self.0.cfg;
+        let sender_callsign = packet.from.split('-').next().unwrap_or_default();
         if !cfg
             .allowed_senders
             .iter()
-            .any(|s| s.to_uppercase() == sender.call().to_string().to_uppercase())
+            .any(|s| s.eq_ignore_ascii_case(sender_callsign))
         {
             return None;
         }
-        let msg = if let AprsData::Message(d) = package.data {
-            d
-        } else {
-            return None;
-        };
-        let receiver = msg
-            .addressee
-            .pipe(|d| String::from_utf8_lossy(&d).to_uppercase());
+        let receiver = packet.addressee.as_deref()?;
         if !cfg
             .allowed_recipients
             .iter()
-            .any(|s| s.to_uppercase() == receiver)
+            .any(|s| s.eq_ignore_ascii_case(receiver))
         {
             return None;
         }
-        if !cfg
-            .allowed_recipients
-            .iter()
-            .any(|s| s.to_uppercase() == receiver)
-        {
+        let body = packet.message.as_deref()?;
+        let (receiver, content) = body.split_once(' ')?;
+        let receiver_email = if receiver.contains('@') {
+            if !cfg.allowed_receiver_emails.is_empty()
+                && !cfg
+                    .allowed_receiver_emails
+                    .iter()
+                    .any(|s| s.eq_ignore_ascii_case(receiver))
+            {
+                self.error(&format!("receiver email {receiver} not allowed"));
+                return None;
+            }
+            receiver.to_string()
+        } else {
+            match self.resolve_alias(&packet.from, receiver) {
+                Some(email) => email,
+                None => {
+                    //the message was rejected already, its retransmits are ignored
+                    if self.0.messenger.already_handled(&packet) {
+                        return None;
+                    }
+                    self.warn(&format!("unknown alias {receiver} from {}", packet.from));
+                    let from = packet.addressee.as_deref().unwrap_or_default();
+                    let text = format!("unknown alias {receiver}, use an email address");
+                    let reply = crate::aprs::message_packet(from, &packet.from, &text, None);
+                    self.0.messenger.mark_handled(&packet);
+                    self.0.messenger.reject(&packet).await;
+                    self.0.messenger.transmit(reply).await;
+                    return None;
+                }
+            }
+        };
+        if self.0.messenger.already_handled(&packet) {
+            self.0.messenger.ack(&packet).await;
             return None;
         }
-        let body = String::from_utf8_lossy(&msg.text);
-        let (receiver_email, content) = body.split_once(' ')?;
-        if !cfg.allowed_receiver_emails.is_empty()
-            && !cfg
-                .allowed_receiver_emails
-                .iter()
-                .any(|s| s.to_uppercase() == receiver_email.to_uppercase())
-        {
-            self.error(&format!("receiver email {receiver_email} not allowed"));
-            return None;
+        if let Some(key) = queue_key(&packet) {
+            if !self.0.queued.lock().insert(key) {
+                return None;
+            }
         }
-        let message = lettre::Message::builder()
-            .from(
-                cfg.from_email
-                    .parse()
-                    .map_err(|e| {
-                        self.error(&format!("invalid from email: {}", e));
-                    })
-                    .ok()?,
-            )
-            .to(receiver_email
-                .parse()
-                .map_err(|e| {
-                    self.error(&format!("invalid receiver email: {}", e));
-                })
-                .ok()?)
-            .date_now()
-            .subject("This email was sent using https://github.com/ta3pks/aprs-agent")
-            .body(content.to_string())
-            .map_err(|e| {
-                self.error(&format!("failed to build email: {}", e));
-            })
-            .ok()?;
-        let credentials = Credentials::new(cfg.smtp_username.clone(), cfg.smtp_password.clone());
-        let (host, port) = cfg.smtp_server.split_once(':').tap_none(|| {
-            self.error(&format!(
-                "invalid smtp server: {} format must be host:port",
-                cfg.smtp_server
-            ));
-        })?;
-        let mailer = lettre::SmtpTransport::relay(host)
-            .map_err(|e| {
-                self.error(&format!("failed to connect to smtp server: {}", e));
+        let render = |template: &str, html: bool| {
+            render_template(template, |name| {
+                let value = template_var(&packet, content, name)?;
+                Some(if html { html_escape(&value) } else { value })
             })
-            .ok()?
-            .port(
-                port.parse()
-                    .map_err(|e| {
-                        self.error(&format!("invalid smtp port: {}", e));
-                    })
-                    .ok()?,
-            )
-            .credentials(credentials)
-            .build();
-        mailer
-            .send(&message)
-            .map_err(|e| {
-                self.error(&format!("failed to send email: {}", e));
-            })
-            .ok()?;
-        let msg_id = String::from_utf8_lossy(&msg.id?).to_string();
-        let path = package
-            .via
-            .iter()
-            .map(|x| match x {
-                aprs_parser::Via::Callsign(c, _) => c.to_string(),
-                aprs_parser::Via::QConstruct(q) => q.as_textual().to_string(),
-            })
-            .collect::<Vec<_>>()
-            .join(",");
-        let sender = sender.to_string();
-        Some(format!("{to}>{sender},{path}::{sender: <9}:ack{msg_id}\n",).into_bytes())
+        };
+        self.enqueue(Outgoing {
+            to_email: receiver_email,
+            subject: render(&cfg.subject_template, false),
+            body: render(&cfg.body_template, false),
+            html: cfg.html_template.as_deref().map(|t| render(t, true)),
+            packet: line.trim_end().to_string(),
+            attempts: 0,
+            next_attempt: 0,
+            file: None,
+        });
+        if cfg.ack_policy == AckPolicy::OnReceipt {
+            self.0.messenger.mark_handled(&packet);
+            self.0.messenger.ack(&packet).await;
+        }
+        None
+    }
+    fn set_own_writer(&self, w: mpsc::Sender<Vec<u8>>) {
+        self.0.messenger.set_writer(Some(w));
+    }
+    fn on_start(&self) {
+        let Some(rx) = self.0.rx.lock().take() else {
+            return;
+        };
+        self.load_outbox();
+        let ext = self.clone();
+        tokio::spawn(async move { ext.run(rx).await });
+    }
+    fn on_disconnected(&self, _reason: &str) {
+        self.0.messenger.set_writer(None);
+    }
+    async fn on_shutdown(&self, deadline: Instant) {
+        while self.0.pending.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let pending = self.0.pending.load(Ordering::SeqCst);
+        if pending > 0 {
+            self.warn(&format!("{pending} emails left in the outbox"));
+        }
     }
 }
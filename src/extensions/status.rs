@@ -0,0 +1,245 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use educe::Educe;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
+
+use super::Extension;
+use crate::{
+    aprs::{packet::Packet, stations, stats},
+    utils::{now_unix, render_template},
+};
+
+/// periodic `>` status reports and `<IGATE` capabilities, both are also sent as answers to
+/// `?IGATE?` queries and directed `?APRSS` and `?IGATE` messages
+#[derive(Debug, Serialize, Deserialize, Clone, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct Config {
+    pub enabled: bool,
+    /// station the reports are sent from, the agent callsign when empty
+    pub source: String,
+    #[educe(Default = "AP4GNT")]
+    pub destination: String,
+    /// comma separated digipeater path after the destination
+    #[educe(Default = "TCPIP*")]
+    pub path: String,
+    /// `{version}`, `{uptime}`, `{received}`, `{messages}`, `{transmitted}`, `{stations}` and
+    /// `{callsign}` are replaced, the result is cut to the 62 characters a status can carry
+    #[educe(Default = "aprs-agent {version} up {uptime}, {received} pkts heard")]
+    pub status_text: String,
+    #[educe(Default = 30)]
+    pub status_interval_mins: u64,
+    #[educe(Default = true)]
+    pub capabilities: bool,
+    #[educe(Default = 60)]
+    pub capabilities_interval_mins: u64,
+    /// stations heard within this many minutes count as local for `LOC_CNT` and `{stations}`
+    #[educe(Default = 60)]
+    pub local_window_mins: u64,
+    #[educe(Default = true)]
+    pub answer_queries: bool,
+}
+
+/// queries of one kind are answered at most this often
+const QUERY_COOLDOWN_SECS: u64 = 60;
+
+#[derive(Clone)]
+pub struct Status(Arc<Inner>);
+struct Inner {
+    cfg: Config,
+    own_writer: Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+    online: watch::Sender<bool>,
+    /// when each kind of query was answered last
+    answered: Mutex<HashMap<&'static str, u64>>,
+}
+
+impl Status {
+    pub fn new(cfg: &Config) -> Self {
+        if !cfg.enabled {
+            panic!("status extension is not enabled but tried to be created");
+        }
+        if cfg.status_interval_mins == 0 || cfg.capabilities_interval_mins == 0 {
+            panic!("status extension needs positive intervals");
+        }
+        if cfg.destination.is_empty() || cfg.destination.len() > 9 {
+            panic!("status destination must be 1 to 9 characters");
+        }
+        Self(Arc::new(Inner {
+            cfg: cfg.clone(),
+            own_writer: Mutex::new(None),
+            online: watch::channel(false).0,
+            answered: Mutex::new(HashMap::new()),
+        }))
+    }
+    fn source(&self) -> String {
+        let cfg = &self.0.cfg;
+        if cfg.source.is_empty() {
+            crate::Config::get().callsign.to_uppercase()
+        } else {
+            cfg.source.to_uppercase()
+        }
+    }
+    fn packet(&self, data: &str) -> String {
+        let cfg = &self.0.cfg;
+        let path = if cfg.path.is_empty() {
+            String::new()
+        } else {
+            format!(",{}", cfg.path)
+        };
+        format!(
+            "{}>{}{path}:{data}\n",
+            self.source(),
+            cfg.destination.to_uppercase()
+        )
+    }
+    fn local_stations(&self) -> usize {
+        stations::heard_within(60 * self.0.cfg.local_window_mins)
+    }
+    fn status(&self) -> String {
+        let stats = stats::snapshot();
+        let text = render_template(&self.0.cfg.status_text, |name| {
+            Some(match name {
+                "version" => env!("CARGO_PKG_VERSION").to_string(),
+                "uptime" => format_uptime(stats.uptime_secs),
+                "received" => stats.received.to_string(),
+                "messages" => stats.messages.to_string(),
+                "transmitted" => stats.transmitted.to_string(),
+                "stations" => self.local_stations().to_string(),
+                "callsign" => self.source(),
+                _ => return None,
+            })
+        });
+        let text = text.replace(['\r', '\n'], " ");
+        self.packet(&format!(">{}", text.chars().take(62).collect::<String>()))
+    }
+    fn capabilities(&self) -> String {
+        let stats = stats::snapshot();
+        self.packet(&format!(
+            "<IGATE,MSG_CNT={},LOC_CNT={}",
+            stats.messages,
+            self.local_stations()
+        ))
+    }
+    async fn run(&self, interval_mins: u64, report: fn(&Self) -> String) {
+        let mut online = self.0.online.subscribe();
+        loop {
+            //pause while offline, the report goes out right after every (re)connect
+            while !*online.borrow_and_update() {
+                if online.changed().await.is_err() {
+                    return;
+                }
+            }
+            let writer = self.0.own_writer.lock().clone();
+            if let Some(writer) = writer {
+                if let Err(e) = writer.send(report(self).into_bytes()).await {
+                    self.error(&format!("failed to send report: {e}"));
+                }
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(60 * interval_mins)) => {}
+                _ = online.changed() => {}
+            }
+        }
+    }
+    /// answers queries unless the same kind was answered within the cooldown
+    fn answer(&self, kind: &'static str) -> Option<Vec<u8>> {
+        let now = now_unix();
+        let mut answered = self.0.answered.lock();
+        if answered
+            .get(kind)
+            .is_some_and(|at| now.saturating_sub(*at) < QUERY_COOLDOWN_SECS)
+        {
+            return None;
+        }
+        answered.insert(kind, now);
+        drop(answered);
+        let packet = match kind {
+            "status" => self.status(),
+            _ if !self.0.cfg.capabilities => return None,
+            _ => self.capabilities(),
+        };
+        Some(packet.into_bytes())
+    }
+}
+
+/// `3d4h12m`, leading zero units are left out
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, mins) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    match (days, hours) {
+        (0, 0) => format!("{mins}m"),
+        (0, _) => format!("{hours}h{mins}m"),
+        _ => format!("{days}d{hours}h{mins}m"),
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for Status {
+    fn name(&self) -> &'static str {
+        "status"
+    }
+    async fn handle(&self, line: &str) -> Option<Vec<u8>> {
+        if !self.0.cfg.answer_queries {
+            return None;
+        }
+        //only queries and messages are decoded
+        let (_, payload) = line.split_once(':')?;
+        if !payload.starts_with(['?', ':']) {
+            return None;
+        }
+        let packet = Packet::parse(line)?;
+        if packet.payload.starts_with("?IGATE?") {
+            return self.answer("capabilities");
+        }
+        if packet.kind() != "message"
+            || !packet
+                .addressee
+                .as_ref()?
+                .eq_ignore_ascii_case(&self.source())
+        {
+            return None;
+        }
+        match packet.message.as_deref()?.trim() {
+            "?APRSS" => self.answer("status"),
+            "?IGATE" => self.answer("capabilities"),
+            _ => None,
+        }
+    }
+    fn set_own_writer(&self, w: mpsc::Sender<Vec<u8>>) {
+        *self.0.own_writer.lock() = Some(w);
+    }
+    fn on_start(&self) {
+        let cfg = &self.0.cfg;
+        let ext = self.clone();
+        let every = cfg.status_interval_mins;
+        tokio::spawn(async move { ext.run(every, Self::status).await });
+        if cfg.capabilities {
+            let ext = self.clone();
+            let every = cfg.capabilities_interval_mins;
+            tokio::spawn(async move { ext.run(every, Self::capabilities).await });
+        }
+    }
+    fn on_connected(&self, _server: &str, _verified: bool) {
+        self.0.online.send_replace(true);
+    }
+    fn on_disconnected(&self, _reason: &str) {
+        *self.0.own_writer.lock() = None;
+        self.0.online.send_replace(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uptime() {
+        assert_eq!(format_uptime(0), "0m");
+        assert_eq!(format_uptime(59), "0m");
+        assert_eq!(format_uptime(3599), "59m");
+        assert_eq!(format_uptime(3600), "1h0m");
+        assert_eq!(format_uptime(86400 + 60), "1d0h1m");
+        assert_eq!(format_uptime(3 * 86400 + 4 * 3600 + 12 * 60), "3d4h12m");
+    }
+}
@@ -0,0 +1,82 @@
+//! a minimal http server standing in for the apis bridges talk to in tests
+
+use std::{collections::HashMap, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// header names are lowercase
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+/// answers requests with the given status and body in turn, the last one is repeated
+/// each answer is delayed by `delay`, returns the base url and the requests as they arrive
+pub async fn serve(
+    responses: Vec<(u16, &'static str)>,
+    delay: Duration,
+) -> (String, mpsc::UnboundedReceiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut n = 0;
+        while let Ok((sock, _)) = listener.accept().await {
+            let (status, body) = responses
+                .get(n)
+                .or(responses.last())
+                .copied()
+                .unwrap_or((200, ""));
+            n += 1;
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let (r, mut w) = sock.into_split();
+                let mut r = BufReader::new(r);
+                let mut line = String::new();
+                r.read_line(&mut line).await.ok();
+                let mut parts = line.split_whitespace();
+                let (method, path) = (parts.next().unwrap_or_default(), parts.next());
+                let mut request = Request {
+                    method: method.to_string(),
+                    path: path.unwrap_or_default().to_string(),
+                    headers: HashMap::new(),
+                    body: String::new(),
+                };
+                loop {
+                    line.clear();
+                    if r.read_line(&mut line).await.unwrap_or(0) == 0 || line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((k, v)) = line.split_once(':') {
+                        request
+                            .headers
+                            .insert(k.trim().to_lowercase(), v.trim().to_string());
+                    }
+                }
+                let len = request
+                    .headers
+                    .get("content-length")
+                    .and_then(|l| l.parse().ok())
+                    .unwrap_or(0);
+                let mut received = vec![0; len];
+                r.read_exact(&mut received).await.ok();
+                request.body = String::from_utf8_lossy(&received).to_string();
+                tx.send(request).ok();
+                tokio::time::sleep(delay).await;
+                let response = format!(
+                    "HTTP/1.1 {status} STUB\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                w.write_all(response.as_bytes()).await.ok();
+            });
+        }
+    });
+    (url, rx)
+}
@@ -0,0 +1,348 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use educe::Educe;
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::{filter::Filter, twitter::fmt_pass, Extension};
+use crate::aprs::{
+    messaging::{Messenger, Outcome},
+    packet::Packet,
+};
+
+/// bridges aprs messages addressed to `alias` to telegram chats and replies back
+/// a telegram message starting with `@CALLSIGN` goes to that station, anything else to the
+/// station that last wrote into the chat or one of the stations mapped to it
+#[derive(Serialize, Deserialize, Clone, Educe)]
+#[educe(Default, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub enabled: bool,
+    #[educe(Debug(method = "fmt_pass"))]
+    pub bot_token: String,
+    #[educe(Default = "https://api.telegram.org")]
+    pub api_url: String,
+    /// aprs stations message this name to reach telegram, replies are sent from it
+    #[educe(Default = "TELEGRAM")]
+    pub alias: String,
+    /// callsign, with or without ssid, to the telegram chat id its messages go to
+    pub chats: HashMap<String, i64>,
+    /// telegram user ids or usernames allowed to send aprs messages
+    pub allowed_users: Vec<String>,
+    #[educe(Default = 30)]
+    pub poll_timeout_secs: u64,
+    /// first retransmit of an unacked aprs message, doubled after every attempt
+    #[educe(Default = 30)]
+    pub retry_secs: u64,
+    #[educe(Default = 5)]
+    pub max_attempts: u32,
+    pub filter: Filter,
+}
+
+#[derive(Deserialize)]
+struct Response<T> {
+    ok: bool,
+    result: Option<T>,
+    description: Option<String>,
+}
+#[derive(Deserialize)]
+struct Update {
+    update_id: i64,
+    message: Option<Message>,
+}
+#[derive(Deserialize)]
+struct Message {
+    chat: Chat,
+    from: Option<User>,
+    text: Option<String>,
+}
+#[derive(Deserialize)]
+struct Chat {
+    id: i64,
+}
+#[derive(Deserialize)]
+struct User {
+    id: i64,
+    username: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Telegram(Arc<Inner>);
+struct Inner {
+    cfg: Config,
+    client: reqwest::Client,
+    messenger: Messenger,
+    /// station that last wrote into a chat, where replies from that chat go by default
+    last_station: Mutex<HashMap<i64, String>>,
+    /// sender and message id of messages being forwarded, retransmits of them are ignored
+    queued: Mutex<HashSet<(String, String)>>,
+}
+
+impl Telegram {
+    pub fn new(cfg: &Config) -> Self {
+        if cfg.bot_token.is_empty() {
+            panic!("telegram extension enabled but no bot token specified");
+        }
+        if cfg.chats.is_empty() {
+            panic!("telegram extension enabled but no chats specified");
+        }
+        if reqwest::Url::parse(&cfg.api_url).is_err() {
+            panic!("telegram extension has an invalid api url {}", cfg.api_url);
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(cfg.poll_timeout_secs + 10))
+            .build()
+            .expect("failed to build http client");
+        Self(Arc::new(Inner {
+            cfg: cfg.clone(),
+            client,
+            messenger: Messenger::new(cfg.retry_secs, cfg.max_attempts),
+            last_station: Mutex::new(HashMap::new()),
+            queued: Mutex::new(HashSet::new()),
+        }))
+    }
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        form: &[(&str, String)],
+    ) -> Result<T, String> {
+        let cfg = &self.0.cfg;
+        let url = format!(
+            "{}/bot{}/{method}",
+            cfg.api_url.trim_end_matches('/'),
+            cfg.bot_token
+        );
+        let body = self
+            .0
+            .client
+            .post(url)
+            .form(form)
+            .send()
+            .await
+            .map_err(|e| e.without_url().to_string())?
+            .text()
+            .await
+            .map_err(|e| e.without_url().to_string())?;
+        let resp = serde_json::from_str::<Response<T>>(&body).map_err(|e| e.to_string())?;
+        match resp.result {
+            Some(result) if resp.ok => Ok(result),
+            _ => Err(resp.description.unwrap_or(body)),
+        }
+    }
+    async fn send_to_chat(&self, chat: i64, text: String) -> Result<(), String> {
+        self.call::<serde_json::Value>(
+            "sendMessage",
+            &[("chat_id", chat.to_string()), ("text", text)],
+        )
+        .await
+        .map(|_| ())
+    }
+    fn chat_of(&self, station: &str) -> Option<i64> {
+        let chats = &self.0.cfg.chats;
+        let call = station.split('-').next().unwrap_or_default();
+        chats
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(station))
+            .or_else(|| chats.iter().find(|(k, _)| k.eq_ignore_ascii_case(call)))
+            .map(|(_, chat)| *chat)
+    }
+    fn is_allowed(&self, user: &User) -> bool {
+        self.0.cfg.allowed_users.iter().any(|u| {
+            u.trim_start_matches('@')
+                .parse::<i64>()
+                .map(|id| id == user.id)
+                .unwrap_or_else(|_| {
+                    user.username
+                        .as_deref()
+                        .is_some_and(|n| n.eq_ignore_ascii_case(u.trim_start_matches('@')))
+                })
+        })
+    }
+    async fn poll(&self) {
+        let mut offset = 0;
+        loop {
+            let form = [
+                ("offset", offset.to_string()),
+                ("timeout", self.0.cfg.poll_timeout_secs.to_string()),
+                ("allowed_updates", r#"["message"]"#.to_string()),
+            ];
+            let updates = match self.call::<Vec<Update>>("getUpdates", &form).await {
+                Ok(updates) => updates,
+                Err(e) => {
+                    self.error(&format!("failed to get updates: {e}"));
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+            for update in updates {
+                offset = offset.max(update.update_id + 1);
+                if let Some(msg) = update.message {
+                    self.relay(msg);
+                }
+            }
+        }
+    }
+    /// sends a telegram message to its aprs station, the chat is told how the delivery went
+    fn relay(&self, msg: Message) {
+        let (Some(user), Some(text)) = (msg.from, msg.text) else {
+            return;
+        };
+        if !self.is_allowed(&user) {
+            self.warn(&format!("ignoring message from telegram user {}", user.id));
+            return;
+        }
+        let chat = msg.chat.id;
+        let (to, text) = match text.strip_prefix('@').and_then(|t| t.split_once(' ')) {
+            Some((to, text)) => (to.to_uppercase(), text.trim().to_string()),
+            None => {
+                let last = self.0.last_station.lock().get(&chat).cloned();
+                let mapped = || {
+                    self.0
+                        .cfg
+                        .chats
+                        .iter()
+                        .find(|(_, c)| **c == chat)
+                        .map(|(station, _)| station.to_uppercase())
+                };
+                match last.or_else(mapped) {
+                    Some(to) => (to, text),
+                    None => return,
+                }
+            }
+        };
+        if text.is_empty() {
+            return;
+        }
+        let ext = self.clone();
+        tokio::spawn(async move {
+            let outcome = ext.0.messenger.send(&ext.0.cfg.alias, &to, &text).await;
+            let status = match outcome {
+                Outcome::Acked => format!("delivered to {to}"),
+                Outcome::Rejected => format!("rejected by {to}"),
+                Outcome::Expired => format!("no ack from {to}, message not delivered"),
+            };
+            if let Err(e) = ext.send_to_chat(chat, status).await {
+                ext.error(&format!("failed to report delivery: {e}"));
+            }
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for Telegram {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+    async fn handle(&self, line: &str) -> Option<Vec<u8>> {
+        let packet = Packet::parse(line)?;
+        if self.0.messenger.handle_reply(&packet) || packet.kind() != "message" {
+            return None;
+        }
+        let alias = &self.0.cfg.alias;
+        if !packet.addressee.as_ref()?.eq_ignore_ascii_case(alias) {
+            return None;
+        }
+        let chat = self.chat_of(&packet.from)?;
+        let text = packet.message.clone()?;
+        if self.0.messenger.already_handled(&packet) {
+            self.0.messenger.ack(&packet).await;
+            return None;
+        }
+        let queue_key = queue_key(&packet);
+        if let Some(ref key) = queue_key {
+            if !self.0.queued.lock().insert(key.clone()) {
+                return None;
+            }
+        }
+        let ext = self.clone();
+        tokio::spawn(async move {
+            match ext
+                .send_to_chat(chat, format!("{}: {text}", packet.from))
+                .await
+            {
+                Ok(()) => {
+                    ext.0
+                        .last_station
+                        .lock()
+                        .insert(chat, packet.from.to_uppercase());
+                    ext.0.messenger.mark_handled(&packet);
+                    ext.0.messenger.ack(&packet).await;
+                }
+                Err(e) => ext.error(&format!("failed to forward message: {e}")),
+            }
+            if let Some(key) = queue_key {
+                ext.0.queued.lock().remove(&key);
+            }
+        });
+        None
+    }
+    fn set_own_writer(&self, w: mpsc::Sender<Vec<u8>>) {
+        self.0.messenger.set_writer(Some(w));
+    }
+    fn on_start(&self) {
+        let ext = self.clone();
+        tokio::spawn(async move { ext.poll().await });
+    }
+    fn on_disconnected(&self, _reason: &str) {
+        self.0.messenger.set_writer(None);
+    }
+}
+
+fn queue_key(packet: &Packet) -> Option<(String, String)> {
+    Some((packet.from.to_uppercase(), packet.message_id.clone()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::stub_http;
+
+    const MESSAGE: &str = "N0CALL-7>APRS,TCPIP*::TELEGRAM :hello{12";
+
+    async fn sent(rx: &mut mpsc::Receiver<Vec<u8>>) -> String {
+        let packet = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await;
+        String::from_utf8(packet.unwrap().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn forwards_retransmits_once() {
+        let (api_url, mut requests) = stub_http::serve(
+            vec![(200, r#"{"ok":true,"result":{}}"#)],
+            Duration::from_millis(200),
+        )
+        .await;
+        let ext = Telegram::new(&Config {
+            bot_token: "t0ken".into(),
+            api_url,
+            chats: HashMap::from([("N0CALL".into(), 42)]),
+            ..Default::default()
+        });
+        let (tx, mut rx) = mpsc::channel(8);
+        ext.set_own_writer(tx);
+        ext.handle(MESSAGE).await;
+        //a retransmit while the first one is still being forwarded is ignored
+        ext.handle(MESSAGE).await;
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.path, "/bott0ken/sendMessage");
+        assert!(request.body.contains("chat_id=42"));
+        assert!(request.body.contains("N0CALL-7%3A+hello"));
+        assert_eq!(
+            sent(&mut rx).await,
+            "TELEGRAM>AP4GNT,TCPIP*::N0CALL-7 :ack12\n"
+        );
+        //once forwarded a retransmit is only acked again
+        ext.handle(MESSAGE).await;
+        assert_eq!(
+            sent(&mut rx).await,
+            "TELEGRAM>AP4GNT,TCPIP*::N0CALL-7 :ack12\n"
+        );
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(requests.try_recv().is_err());
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -1,15 +1,21 @@
 use std::{
+    collections::{HashMap, HashSet},
     fmt::{self, Formatter},
-    marker::PhantomData,
+    sync::Arc,
+    time::Duration,
 };
 
-use aprs_parser::AprsData;
 use educe::Educe;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use tap::Pipe;
+use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
 
-use super::Extension;
-fn fmt_pass(v: &String, f: &mut Formatter) -> fmt::Result {
+use super::{filter::Filter, Extension};
+use crate::{
+    aprs::{messaging::Messenger, packet::Packet},
+    utils::split_text,
+};
+pub(super) fn fmt_pass(v: &str, f: &mut Formatter) -> fmt::Result {
     let fst = v.chars().take(3).collect::<String>();
     let lst = if v.len() > 3 {
         v.chars().skip(v.len() - 3).collect::<String>()
@@ -19,8 +25,20 @@ fn fmt_pass(v: &String, f: &mut Formatter) -> fmt::Result {
     f.write_str(&format!("{fst}xxxx{lst}"))
 }
 
+/// how the sender learns that a post failed
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFailure {
+    Rej,
+    /// a message like `TW ERR: rate limited`
+    Reply,
+    #[default]
+    Both,
+}
+
 #[derive(Serialize, Deserialize, Clone, Educe)]
 #[educe(Default, Debug)]
+#[serde(default)]
 pub struct Config {
     pub enabled: bool,
     #[educe(Debug(method = "fmt_pass"))]
@@ -39,8 +57,39 @@ pub struct Config {
     pub allowed_recepients: Vec<String>,
     #[educe(Default(expression = r#"vec!["TA3PKS"].into_iter().map(Into::into).collect()"#))]
     pub allowed_senders: Vec<String>,
+    /// messages a station sends within this many seconds of each other are joined into one post
+    #[educe(Default = 20)]
+    pub thread_window_secs: u64,
+    /// longer posts are split into a reply thread
+    #[educe(Default = 280)]
+    pub max_tweet_chars: usize,
+    /// retries for rate limits and network or server errors, the wait doubles after each one
+    #[educe(Default = 3)]
+    pub max_retries: u32,
+    #[educe(Default = 60)]
+    pub retry_backoff_secs: u64,
+    pub on_failure: OnFailure,
+    pub filter: Filter,
+}
+
+/// messages of a station waiting for the thread window to close
+struct Pending {
+    messages: Vec<Packet>,
+    /// bumped with every message so only the timer of the last one flushes
+    generation: u64,
+}
+
+#[derive(Clone)]
+pub struct Twitter(Arc<Inner>);
+struct Inner {
+    cfg: Config,
+    messenger: Messenger,
+    pending: Mutex<HashMap<String, Pending>>,
+    /// sender and message id of messages waiting for or being posted, retransmits of them are ignored
+    queued: Mutex<HashSet<(String, String)>>,
+    /// threads being posted after their window closed, awaited on shutdown so their acks go out
+    posts: Mutex<Vec<JoinHandle<()>>>,
 }
-pub struct Twitter(PhantomData<()>);
 impl Twitter {
     pub fn new(cfg: &Config) -> Self {
         if !cfg.enabled {
@@ -56,37 +105,153 @@ impl Twitter {
         if cfg.allowed_recepients.is_empty() || cfg.allowed_senders.is_empty() {
             panic!("Twitter extension enabled but no allowed recepients or senders specified");
         }
-        Self(PhantomData)
+        Self(Arc::new(Inner {
+            cfg: cfg.clone(),
+            messenger: Messenger::default(),
+            pending: Mutex::new(HashMap::new()),
+            queued: Mutex::new(HashSet::new()),
+            posts: Mutex::new(vec![]),
+        }))
     }
-    async fn send_tweet(&self, tweet: String) {
+    /// splits the text into tweets, the source line and hash tag go at the end of the last one
+    fn compose(&self, text: &str, footer: &str) -> Vec<String> {
+        let max = self.0.cfg.max_tweet_chars.max(40);
+        let footer = if self.0.cfg.add_hash_tag {
+            format!("{footer} #APRS")
+        } else {
+            footer.to_string()
+        };
+        let mut tweets = split_text(text, max);
+        match tweets.last_mut() {
+            Some(last) if last.chars().count() + footer.chars().count() < max => {
+                last.push('\n');
+                last.push_str(&footer);
+            }
+            _ => tweets.extend(split_text(&footer, max)),
+        }
+        tweets
+    }
+    /// posts the tweets still missing from the thread, progress is kept in `thread` for a retry
+    async fn send_thread(&self, thread: &mut Thread) -> Result<(), Failure> {
         let Config {
             api_key,
             api_secret,
             access_token_key,
             access_token_secret,
-            add_hash_tag,
-            enabled: _,
-            allowed_recepients: _,
-            allowed_senders: _,
-        } = &crate::Config::get().extensions.twitter;
-        let tweet = if *add_hash_tag {
-            format!("{} #APRS", tweet)
-        } else {
-            tweet
-        };
+            ..
+        } = &self.0.cfg;
         let token = twitter_v2::authorization::Oauth1aToken::new(
             api_key,
             api_secret,
             access_token_key,
             access_token_secret,
         );
-        if let Err(e) = twitter_v2::TwitterApi::new(token)
-            .post_tweet()
-            .text(tweet)
-            .send()
-            .await
-        {
-            self.error(&format!("tweet error: {:#?}", e))
+        let api = twitter_v2::TwitterApi::new(token);
+        while let Some(tweet) = thread.tweets.get(thread.posted) {
+            let mut post = api.post_tweet();
+            post.text(tweet.clone());
+            if let Some(id) = thread.reply_to {
+                post.in_reply_to_tweet_id(id);
+            }
+            let resp = post.send().await.map_err(|e| {
+                self.error(&format!("tweet error: {:#?}", e));
+                Failure::from(e)
+            })?;
+            thread.reply_to = resp.data().map(|t| t.id.as_u64());
+            thread.posted += 1;
+        }
+        Ok(())
+    }
+    /// posts the collected messages of a station, retransmits are ignored until it is done
+    async fn flush(&self, messages: Vec<Packet>) {
+        self.post(&messages).await;
+        let mut queued = self.0.queued.lock();
+        for key in messages.iter().filter_map(queue_key) {
+            queued.remove(&key);
+        }
+    }
+    /// transient errors are retried with backoff
+    /// the messages are acked once everything is posted and rejected otherwise
+    async fn post(&self, messages: &[Packet]) {
+        let Some(first) = messages.first() else {
+            return;
+        };
+        let cfg = &self.0.cfg;
+        let text = messages
+            .iter()
+            .filter_map(|m| m.message.as_deref())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let footer = format!("from {}>{},{}", first.from, first.to, first.path.join(","));
+        let mut thread = Thread {
+            tweets: self.compose(&text, &footer),
+            posted: 0,
+            reply_to: None,
+        };
+        let mut backoff = Duration::from_secs(cfg.retry_backoff_secs);
+        let mut attempt = 0;
+        let result = loop {
+            match self.send_thread(&mut thread).await {
+                Err(Failure::Transient(reason)) if attempt < cfg.max_retries => {
+                    self.warn(&format!("{reason}, retrying in {backoff:?}"));
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                res => break res,
+            }
+        };
+        let reason = match result {
+            Ok(()) => {
+                for msg in messages {
+                    self.0.messenger.mark_handled(msg);
+                    self.0.messenger.ack(msg).await;
+                }
+                return;
+            }
+            Err(Failure::Transient(reason) | Failure::Permanent(reason)) => reason,
+        };
+        if matches!(cfg.on_failure, OnFailure::Rej | OnFailure::Both) {
+            for msg in messages {
+                self.0.messenger.reject(msg).await;
+            }
+        }
+        if matches!(cfg.on_failure, OnFailure::Reply | OnFailure::Both) {
+            let from = first.addressee.as_deref().unwrap_or_default();
+            let text = format!("TW ERR: {reason}");
+            let reply = crate::aprs::message_packet(from, &first.from, &text, None);
+            self.0.messenger.transmit(reply).await;
+        }
+    }
+}
+
+fn queue_key(packet: &Packet) -> Option<(String, String)> {
+    Some((packet.from.to_uppercase(), packet.message_id.clone()?))
+}
+
+/// progress of a thread so a retry continues where the last attempt stopped
+struct Thread {
+    tweets: Vec<String>,
+    posted: usize,
+    reply_to: Option<u64>,
+}
+
+/// short reasons that fit an aprs message, transient ones are retried
+enum Failure {
+    Transient(String),
+    Permanent(String),
+}
+impl From<twitter_v2::Error> for Failure {
+    fn from(e: twitter_v2::Error) -> Self {
+        match e {
+            twitter_v2::Error::Request(_) => Self::Transient("network error".into()),
+            twitter_v2::Error::Api(e) => match e.status.as_u16() {
+                429 => Self::Transient("rate limited".into()),
+                s if s >= 500 => Self::Transient(format!("server error {s}")),
+                401 | 403 => Self::Permanent("not authorized".into()),
+                s => Self::Permanent(format!("rejected {s}")),
+            },
+            _ => Self::Permanent("post failed".into()),
         }
     }
 }
@@ -97,61 +262,88 @@ impl super::Extension for Twitter {
         "twitter"
     }
     async fn handle(&self, line: &str) -> Option<Vec<u8>> {
-        let cfg = &crate::Config::get().extensions.twitter;
-        if !cfg.enabled {
-            return None;
-        }
-        let package = aprs_parser::AprsPacket::decode_textual(line.as_bytes()).ok()?;
-        if package.data.data_type_identifier() != b':' {
+        let cfg = &self.0.cfg;
+        let packet = Packet::parse(line)?;
+        if packet.kind() != "message" {
             return None;
         }
-        let ssid = &package.from;
-        let sender_callsign = ssid.call().to_string();
-        let ssid = ssid.to_string();
+        let sender_callsign = packet.from.split('-').next().unwrap_or_default();
         if !cfg
             .allowed_senders
             .iter()
-            .any(|x| x.to_uppercase() == sender_callsign.to_uppercase())
+            .any(|x| x.eq_ignore_ascii_case(sender_callsign))
         {
             return None;
         }
-        let path = package
-            .via
-            .iter()
-            .map(|x| match x {
-                aprs_parser::Via::Callsign(c, _) => c.to_string(),
-                aprs_parser::Via::QConstruct(q) => q.as_textual().to_string(),
-            })
-            .collect::<Vec<_>>()
-            .join(",");
-        let to = package.to().map(ToString::to_string).unwrap_or_default();
-        let msg = if let AprsData::Message(m) = package.data {
-            m
-        } else {
+        if !cfg.allowed_recepients.contains(packet.addressee.as_ref()?) {
             return None;
-        };
-        let recepient = msg
-            .addressee
-            .as_slice()
-            .pipe(String::from_utf8_lossy)
-            .to_string();
-        if !cfg.allowed_recepients.contains(&recepient) {
+        }
+        if packet.message.as_deref().unwrap_or_default().is_empty() {
+            return None;
+        }
+        if self.0.messenger.already_handled(&packet) {
+            self.0.messenger.ack(&packet).await;
             return None;
         }
-        let msg_id = msg.id.unwrap_or_default().pipe(|id| {
-            if id.is_empty() {
-                String::new()
-            } else {
-                String::from_utf8_lossy(&id).to_string()
+        //the station retransmits until it sees the ack, which only comes after the post
+        //so messages stay queued from the thread window until the post is done
+        if let Some(key) = queue_key(&packet) {
+            if !self.0.queued.lock().insert(key) {
+                return None;
+            }
+        }
+        let station = packet.from.to_uppercase();
+        let generation = {
+            let mut pending = self.0.pending.lock();
+            let entry = pending.entry(station.clone()).or_insert(Pending {
+                messages: vec![],
+                generation: 0,
+            });
+            entry.messages.push(packet);
+            entry.generation += 1;
+            entry.generation
+        };
+        let ext = self.clone();
+        let window = Duration::from_secs(cfg.thread_window_secs);
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            //taken and spawned under the lock, so on_shutdown either flushes them itself or awaits the post
+            let mut pending = ext.0.pending.lock();
+            let messages = match pending.get(&station) {
+                Some(p) if p.generation == generation => pending.remove(&station),
+                _ => None,
+            };
+            if let Some(p) = messages {
+                let post = tokio::spawn({
+                    let ext = ext.clone();
+                    async move { ext.flush(p.messages).await }
+                });
+                let mut posts = ext.0.posts.lock();
+                posts.retain(|p| !p.is_finished());
+                posts.push(post);
             }
         });
-        let msg = String::from_utf8_lossy(&msg.text);
-        self.send_tweet(format!("{msg}\nfrom {ssid}>{to},{path}"))
-            .await;
-        if msg.is_empty() {
-            None
-        } else {
-            Some(format!("{recepient}>{ssid},{path}::{ssid: <9}:ack{msg_id}\n",).into_bytes())
+        None
+    }
+    fn set_own_writer(&self, w: mpsc::Sender<Vec<u8>>) {
+        self.0.messenger.set_writer(Some(w));
+    }
+    fn on_disconnected(&self, _reason: &str) {
+        self.0.messenger.set_writer(None);
+    }
+    async fn on_shutdown(&self, deadline: Instant) {
+        let pending = std::mem::take(&mut *self.0.pending.lock());
+        let posts = std::mem::take(&mut *self.0.posts.lock());
+        let flush = async {
+            for (_, p) in pending {
+                self.flush(p.messages).await;
+            }
+            for post in posts {
+                post.await.ok();
+            }
+        };
+        if tokio::time::timeout_at(deadline, flush).await.is_err() {
+            self.warn("pending tweets were not posted before the shutdown deadline");
         }
     }
 }
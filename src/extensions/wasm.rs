@@ -0,0 +1,274 @@
+use std::path::PathBuf;
+
+use educe::Educe;
+use serde::{Deserialize, Serialize};
+
+use super::{filter::Filter, outbound::Policy};
+#[cfg(feature = "wasm")]
+pub use host::WasmExtension;
+
+/// a sandboxed plugin compiled to a wasi module, only available when built with the `wasm` feature
+///
+/// abi: the module exports `memory`, `alloc(len: i32) -> i32`, `dealloc(ptr: i32, len: i32)` and
+/// `handle(ptr: i32, len: i32) -> i64`
+/// the host writes the packet as json (see `aprs::packet::Packet`) into memory returned by `alloc`
+/// and calls `handle`, which returns `(ptr << 32) | len` of its output or 0 for none
+/// the output is zero or more raw aprs packets separated by newlines that are sent to aprs-is,
+/// lines breaking the outbound policy are dropped and logged
+/// once the output is read the host hands both the input and the output buffer to `dealloc`
+/// a reactor style `_initialize` export is called once after instantiation, stdout and stderr are inherited
+#[derive(Debug, Serialize, Deserialize, Clone, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct Config {
+    #[educe(Default = true)]
+    pub enabled: bool,
+    /// the extension shows up as `wasm:<name>` in logs
+    pub name: String,
+    pub path: PathBuf,
+    /// linear memory limit of the module
+    #[educe(Default = 16)]
+    pub max_memory_mb: usize,
+    /// wasm fuel available to a single handle call, roughly one unit per instruction
+    /// it bounds the instructions a call executes, `timeout_ms` bounds the time they take
+    #[educe(Default = 10_000_000)]
+    pub fuel_per_call: u64,
+    /// wall clock time a single call may run before it is interrupted, checked every 10ms
+    #[educe(Default = 1000)]
+    pub timeout_ms: u64,
+    /// sources and rate of the packets the module returns
+    #[serde(flatten)]
+    pub outbound: Policy,
+    pub filter: Filter,
+}
+
+#[cfg(feature = "wasm")]
+mod host {
+    use std::{sync::Arc, time::Duration};
+
+    use parking_lot::Mutex;
+    use wasmtime::{Engine, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+    use wasmtime_wasi::{preview1::WasiP1Ctx, WasiCtxBuilder};
+
+    use super::{
+        super::{outbound::Outbound, Extension},
+        Config,
+    };
+    use crate::aprs::packet::Packet;
+
+    /// how often the epoch of an engine advances, the resolution of `timeout_ms`
+    const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+    struct State {
+        wasi: WasiP1Ctx,
+        limits: StoreLimits,
+    }
+
+    struct Plugin {
+        store: Store<State>,
+        instance: Instance,
+    }
+
+    #[derive(Clone)]
+    pub struct WasmExtension(Arc<Inner>);
+    struct Inner {
+        name: &'static str,
+        cfg: Config,
+        engine: Engine,
+        module: Module,
+        linker: Linker<State>,
+        /// dropped after a trap so the next call starts from a fresh instance
+        plugin: Mutex<Option<Plugin>>,
+        outbound: Outbound,
+    }
+
+    impl WasmExtension {
+        pub fn new(cfg: &Config) -> Self {
+            if cfg.name.is_empty() {
+                panic!("wasm extension requires a name");
+            }
+            if cfg.timeout_ms == 0 {
+                panic!("wasm extension {} needs a positive timeout_ms", cfg.name);
+            }
+            let mut config = wasmtime::Config::new();
+            config.consume_fuel(true);
+            config.epoch_interruption(true);
+            let engine = Engine::new(&config).expect("failed to create wasm engine");
+            //the extension lives as long as the agent, so does the thread driving its deadlines
+            let ticker = engine.clone();
+            std::thread::spawn(move || loop {
+                std::thread::sleep(EPOCH_TICK);
+                ticker.increment_epoch();
+            });
+            let module = Module::from_file(&engine, &cfg.path).unwrap_or_else(|e| {
+                panic!(
+                    "wasm extension {} failed to load {:?}: {e}",
+                    cfg.name, cfg.path
+                )
+            });
+            for export in ["memory", "alloc", "dealloc", "handle"] {
+                if module.get_export(export).is_none() {
+                    panic!("wasm extension {} does not export {export}", cfg.name);
+                }
+            }
+            let mut linker = Linker::new(&engine);
+            wasmtime_wasi::preview1::add_to_linker_sync(&mut linker, |s: &mut State| &mut s.wasi)
+                .expect("failed to link wasi");
+            Self(Arc::new(Inner {
+                name: Box::leak(format!("wasm:{}", cfg.name).into_boxed_str()),
+                cfg: cfg.clone(),
+                engine,
+                module,
+                linker,
+                plugin: Mutex::new(None),
+                outbound: cfg.outbound.build(),
+            }))
+        }
+        /// epoch ticks a call may take before it traps
+        fn deadline_ticks(&self) -> u64 {
+            self.0
+                .cfg
+                .timeout_ms
+                .div_ceil(EPOCH_TICK.as_millis() as u64)
+        }
+        fn instantiate(&self) -> wasmtime::Result<Plugin> {
+            let inner = &self.0;
+            let state = State {
+                wasi: WasiCtxBuilder::new()
+                    .inherit_stdout()
+                    .inherit_stderr()
+                    .build_p1(),
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(inner.cfg.max_memory_mb * 1024 * 1024)
+                    .instances(1)
+                    .build(),
+            };
+            let mut store = Store::new(&inner.engine, state);
+            store.limiter(|s| &mut s.limits);
+            store.set_fuel(inner.cfg.fuel_per_call)?;
+            store.set_epoch_deadline(self.deadline_ticks());
+            let instance = inner.linker.instantiate(&mut store, &inner.module)?;
+            if let Ok(init) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+                init.call(&mut store, ())?;
+            }
+            Ok(Plugin { store, instance })
+        }
+        fn call(&self, input: &[u8]) -> wasmtime::Result<Vec<u8>> {
+            let mut plugin = self.0.plugin.lock();
+            let plugin = match plugin.as_mut() {
+                Some(plugin) => plugin,
+                None => plugin.insert(self.instantiate()?),
+            };
+            let Plugin { store, instance } = plugin;
+            store.set_fuel(self.0.cfg.fuel_per_call)?;
+            store.set_epoch_deadline(self.deadline_ticks());
+            let memory = instance
+                .get_memory(&mut *store, "memory")
+                .ok_or_else(|| wasmtime::Error::msg("module does not export memory"))?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut *store, "alloc")?;
+            let dealloc = instance.get_typed_func::<(i32, i32), ()>(&mut *store, "dealloc")?;
+            let handle = instance.get_typed_func::<(i32, i32), i64>(&mut *store, "handle")?;
+            let len = i32::try_from(input.len())?;
+            let ptr = alloc.call(&mut *store, len)?;
+            memory.write(&mut *store, ptr as u32 as usize, input)?;
+            let packed = handle.call(&mut *store, (ptr, len))?;
+            dealloc.call(&mut *store, (ptr, len))?;
+            if packed == 0 {
+                return Ok(vec![]);
+            }
+            let (out_ptr, out_len) = ((packed >> 32) as i32, packed as i32);
+            let mut out = vec![0; out_len as u32 as usize];
+            memory.read(&*store, out_ptr as u32 as usize, &mut out)?;
+            dealloc.call(&mut *store, (out_ptr, out_len))?;
+            Ok(out)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Extension for WasmExtension {
+        fn name(&self) -> &'static str {
+            self.0.name
+        }
+        async fn handle(&self, line: &str) -> Option<Vec<u8>> {
+            let packet = Packet::parse(line)?;
+            let input = serde_json::to_vec(&packet).ok()?;
+            let ext = self.clone();
+            //the module runs synchronously and may burn its whole fuel, keep it off the async workers
+            let res = tokio::task::spawn_blocking(move || ext.call(&input))
+                .await
+                .ok()?;
+            let out = match res {
+                Ok(out) => out,
+                Err(e) => {
+                    self.error(&format!("module failed, it will be restarted: {e:#}"));
+                    self.0.plugin.lock().take();
+                    return None;
+                }
+            };
+            let packets = String::from_utf8_lossy(&out)
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .filter_map(|l| {
+                    self.0
+                        .outbound
+                        .packet(l.trim())
+                        .map_err(|e| self.error(&e))
+                        .ok()
+                })
+                .collect::<String>();
+            if packets.is_empty() {
+                None
+            } else {
+                Some(packets.into_bytes())
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// `handle` echoes its input or loops forever when `looping` is set
+        fn module(name: &str, looping: bool) -> WasmExtension {
+            let body = if looping {
+                "(loop br 0) i64.const 0"
+            } else {
+                "local.get 0 i64.extend_i32_u i64.const 32 i64.shl local.get 1 i64.extend_i32_u i64.or"
+            };
+            let wat = format!(
+                r#"(module
+                    (memory (export "memory") 1)
+                    (func (export "alloc") (param i32) (result i32) i32.const 16)
+                    (func (export "dealloc") (param i32 i32))
+                    (func (export "handle") (param i32 i32) (result i64) {body}))"#
+            );
+            let path =
+                std::env::temp_dir().join(format!("aprs-agent-{name}-{}.wat", std::process::id()));
+            std::fs::write(&path, wat).unwrap();
+            let ext = WasmExtension::new(&Config {
+                name: name.into(),
+                path: path.clone(),
+                fuel_per_call: u64::MAX,
+                timeout_ms: 50,
+                ..Default::default()
+            });
+            std::fs::remove_file(path).ok();
+            ext
+        }
+
+        #[test]
+        fn echo() {
+            let ext = module("echo", false);
+            assert_eq!(ext.call(b"N0CALL>APRS:>hi").unwrap(), b"N0CALL>APRS:>hi");
+            assert_eq!(ext.call(b"again").unwrap(), b"again");
+        }
+
+        #[test]
+        fn timeout() {
+            let ext = module("loop", true);
+            let started = std::time::Instant::now();
+            assert!(ext.call(b"x").is_err());
+            assert!(started.elapsed() < Duration::from_secs(5));
+        }
+    }
+}
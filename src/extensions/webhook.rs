@@ -0,0 +1,483 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use educe::Educe;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, time::Instant};
+
+use super::{
+    filter::Filter,
+    queue::{QueueSettings, WorkQueue},
+    Extension,
+};
+use crate::{
+    aprs::{messaging::Messenger, packet::Packet},
+    utils::render_template,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Reply {
+    #[default]
+    None,
+    /// ack the aprs message once the request succeeded
+    Ack,
+    /// ack the aprs message and answer the sender with the first line of the response body
+    ResponseText,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// delivers matching packets to an http endpoint
+#[derive(Debug, Serialize, Deserialize, Clone, Educe)]
+#[educe(Default)]
+#[serde(default)]
+pub struct Config {
+    #[educe(Default = true)]
+    pub enabled: bool,
+    /// the extension shows up as `webhook:<name>` in logs
+    pub name: String,
+    #[educe(Default = "http://127.0.0.1:8080/aprs")]
+    pub url: String,
+    #[educe(Default = "POST")]
+    pub method: String,
+    pub headers: HashMap<String, String>,
+    pub bearer_token: Option<String>,
+    pub basic_auth: Option<BasicAuth>,
+    /// body with `{field}` placeholders of the packet, the packet is sent as json when unset
+    pub body_template: Option<String>,
+    #[educe(Default = "application/json")]
+    pub content_type: String,
+    #[educe(Default = 10)]
+    pub timeout_secs: u64,
+    #[educe(Default = 5)]
+    pub max_retries: u32,
+    #[educe(Default = 2)]
+    pub initial_backoff_secs: u64,
+    #[educe(Default = 300)]
+    pub max_backoff_secs: u64,
+    /// pending deliveries are kept in this directory so they survive restarts
+    pub queue_dir: Option<PathBuf>,
+    /// bounds the deliveries waiting for the endpoint, `workers` of them are sent at a time
+    pub queue: QueueSettings,
+    pub reply: Reply,
+    /// stations the webhook answers for, only messages addressed to one of them get a reply
+    /// and the reply is sent from that station
+    pub reply_aliases: Vec<String>,
+    pub filter: Filter,
+}
+
+/// a single request waiting to be delivered, serialized into the queue dir
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Delivery {
+    body: String,
+    /// alias the message was addressed to, replies are sent from it, empty when no reply is due
+    reply_from: String,
+    /// the packet the delivery was made of, needed to ack and answer it
+    packet: String,
+    #[serde(skip)]
+    file: Option<PathBuf>,
+}
+impl Delivery {
+    /// sender and message id of messages that are answered, retransmits of them are not delivered again
+    fn key(&self) -> Option<(String, String)> {
+        if self.reply_from.is_empty() {
+            return None;
+        }
+        let packet = Packet::parse(&self.packet)?;
+        Some((packet.from.to_uppercase(), packet.message_id?))
+    }
+}
+
+#[derive(Clone)]
+pub struct Webhook(Arc<Inner>);
+struct Inner {
+    name: &'static str,
+    cfg: Config,
+    client: reqwest::Client,
+    queue: WorkQueue<Delivery>,
+    messenger: Messenger,
+    /// keys of answered messages that are queued or being delivered
+    queued: Mutex<HashSet<(String, String)>>,
+}
+
+enum Failure {
+    Permanent(String),
+    Transient(String),
+}
+
+impl Webhook {
+    pub fn new(cfg: &Config) -> Self {
+        if cfg.name.is_empty() {
+            panic!("webhook extension requires a name");
+        }
+        if reqwest::Url::parse(&cfg.url).is_err() {
+            panic!("webhook {} has an invalid url {}", cfg.name, cfg.url);
+        }
+        if reqwest::Method::from_bytes(cfg.method.as_bytes()).is_err() {
+            panic!("webhook {} has an invalid method {}", cfg.name, cfg.method);
+        }
+        if cfg.reply != Reply::None && (cfg.filter.is_empty() || cfg.reply_aliases.is_empty()) {
+            panic!(
+                "webhook {} can only reply with a filter and reply_aliases configured",
+                cfg.name
+            );
+        }
+        if let Some(ref dir) = cfg.queue_dir {
+            std::fs::create_dir_all(dir).expect("failed to create webhook queue dir");
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(cfg.timeout_secs))
+            .build()
+            .expect("failed to build http client");
+        Self(Arc::new(Inner {
+            name: Box::leak(format!("webhook:{}", cfg.name).into_boxed_str()),
+            cfg: cfg.clone(),
+            client,
+            queue: WorkQueue::new(cfg.queue.clone()),
+            messenger: Messenger::default(),
+            queued: Mutex::new(HashSet::new()),
+        }))
+    }
+    async fn enqueue(&self, mut delivery: Delivery) {
+        if let Some(ref dir) = self.0.cfg.queue_dir {
+            let file = dir.join(format!(
+                "{}.json",
+                std::time::UNIX_EPOCH
+                    .elapsed()
+                    .unwrap_or_default()
+                    .as_nanos()
+            ));
+            match serde_json::to_vec(&delivery).map(|json| std::fs::write(&file, json)) {
+                Ok(Ok(())) => delivery.file = Some(file),
+                _ => self.warn("failed to persist delivery, keeping it in memory only"),
+            }
+        }
+        if let Some(dropped) = self.0.queue.push(delivery).await {
+            let count = self.0.queue.dropped();
+            self.warn(&format!(
+                "queue full, dropping a delivery ({count} dropped so far)"
+            ));
+            self.finish(&dropped);
+        }
+    }
+    /// forgets a delivery that was sent or dropped
+    fn finish(&self, delivery: &Delivery) {
+        if let Some(ref file) = delivery.file {
+            std::fs::remove_file(file).ok();
+        }
+        if let Some(key) = delivery.key() {
+            self.0.queued.lock().remove(&key);
+        }
+    }
+    /// deliveries left over in the queue dir by a previous run
+    async fn load_queue(&self) {
+        let Some(ref dir) = self.0.cfg.queue_dir else {
+            return;
+        };
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        let mut files = entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|e| e == "json"))
+            .collect::<Vec<_>>();
+        files.sort();
+        for file in files {
+            let delivery = std::fs::read(&file)
+                .ok()
+                .and_then(|b| serde_json::from_slice::<Delivery>(&b).ok());
+            let Some(delivery) = delivery else {
+                self.warn(&format!("ignoring unreadable queue file {file:?}"));
+                continue;
+            };
+            if let Some(key) = delivery.key() {
+                self.0.queued.lock().insert(key);
+            }
+            let delivery = Delivery {
+                file: Some(file),
+                ..delivery
+            };
+            if let Some(dropped) = self.0.queue.push(delivery).await {
+                self.warn("queue full, dropping a delivery left by the previous run");
+                self.finish(&dropped);
+            }
+        }
+    }
+    async fn run(&self) {
+        loop {
+            let (delivery, _processing) = self.0.queue.pop().await;
+            self.deliver(&delivery).await;
+            self.finish(&delivery);
+        }
+    }
+    async fn deliver(&self, delivery: &Delivery) {
+        let cfg = &self.0.cfg;
+        let mut backoff = Duration::from_secs(cfg.initial_backoff_secs);
+        for attempt in 0..=cfg.max_retries {
+            match self.send(&delivery.body).await {
+                Ok(text) => {
+                    self.reply(delivery, &text).await;
+                    return;
+                }
+                Err(Failure::Permanent(e)) => {
+                    self.error(&format!("delivery failed permanently: {e}"));
+                    return;
+                }
+                Err(Failure::Transient(e)) if attempt < cfg.max_retries => {
+                    self.warn(&format!("delivery failed, retrying in {backoff:?}: {e}"));
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(Duration::from_secs(cfg.max_backoff_secs));
+                }
+                Err(Failure::Transient(e)) => {
+                    self.error(&format!("delivery failed after {attempt} retries: {e}"));
+                }
+            }
+        }
+    }
+    async fn send(&self, body: &str) -> Result<String, Failure> {
+        let cfg = &self.0.cfg;
+        let method = reqwest::Method::from_bytes(cfg.method.as_bytes()).unwrap_or_default();
+        let mut req = self
+            .0
+            .client
+            .request(method, &cfg.url)
+            .header(reqwest::header::CONTENT_TYPE, &cfg.content_type)
+            .body(body.to_string());
+        for (k, v) in &cfg.headers {
+            req = req.header(k, v);
+        }
+        if let Some(ref token) = cfg.bearer_token {
+            req = req.bearer_auth(token);
+        }
+        if let Some(ref auth) = cfg.basic_auth {
+            req = req.basic_auth(&auth.username, Some(&auth.password));
+        }
+        let resp = req
+            .send()
+            .await
+            .map_err(|e| Failure::Transient(e.to_string()))?;
+        let status = resp.status();
+        let text = resp.text().await.unwrap_or_default();
+        if status.is_success() {
+            Ok(text)
+        } else if status.is_client_error()
+            && status != reqwest::StatusCode::REQUEST_TIMEOUT
+            && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+        {
+            Err(Failure::Permanent(format!("{status}: {text}")))
+        } else {
+            Err(Failure::Transient(format!("{status}: {text}")))
+        }
+    }
+    /// acks a delivered message and answers it with the response if configured
+    async fn reply(&self, delivery: &Delivery, text: &str) {
+        if delivery.reply_from.is_empty() || self.0.cfg.reply == Reply::None {
+            return;
+        }
+        let Some(packet) = Packet::parse(&delivery.packet) else {
+            return;
+        };
+        let messenger = &self.0.messenger;
+        messenger.mark_handled(&packet);
+        let mut sent = true;
+        if let Some(ref id) = packet.message_id {
+            let ack = crate::aprs::ack_packet(&delivery.reply_from, &packet.from, id);
+            sent &= messenger.transmit(ack).await;
+        }
+        let text = text.lines().next().unwrap_or_default().trim();
+        if self.0.cfg.reply == Reply::ResponseText && !text.is_empty() {
+            let text = text
+                .chars()
+                .take(crate::aprs::MAX_MESSAGE_LEN)
+                .collect::<String>();
+            let answer =
+                crate::aprs::message_packet(&delivery.reply_from, &packet.from, &text, None);
+            sent &= messenger.transmit(answer).await;
+        }
+        if !sent {
+            self.warn("not connected, reply dropped");
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for Webhook {
+    fn name(&self) -> &'static str {
+        self.0.name
+    }
+    async fn handle(&self, line: &str) -> Option<Vec<u8>> {
+        let packet = Packet::parse(line)?;
+        let body = match self.0.cfg.body_template {
+            Some(ref template) => render_template(template, |name| packet.var(name)),
+            None => serde_json::to_string(&packet).ok()?,
+        };
+        //only messages to one of our aliases are answered so no other station is impersonated
+        let reply_from = match packet.addressee {
+            Some(ref to) if packet.kind() == "message" && self.0.cfg.reply != Reply::None => self
+                .0
+                .cfg
+                .reply_aliases
+                .iter()
+                .find(|a| a.eq_ignore_ascii_case(to))
+                .map(|a| a.to_uppercase()),
+            _ => None,
+        };
+        let delivery = Delivery {
+            body,
+            reply_from: reply_from.unwrap_or_default(),
+            packet: line.trim_end().to_string(),
+            file: None,
+        };
+        //stations retransmit until they see the ack, answered messages are delivered once
+        if let Some(key) = delivery.key() {
+            if self.0.messenger.already_handled(&packet) {
+                let ack = crate::aprs::ack_packet(&delivery.reply_from, &key.0, &key.1);
+                self.0.messenger.transmit(ack).await;
+                return None;
+            }
+            if !self.0.queued.lock().insert(key) {
+                return None;
+            }
+        }
+        self.enqueue(delivery).await;
+        None
+    }
+    fn set_own_writer(&self, w: mpsc::Sender<Vec<u8>>) {
+        self.0.messenger.set_writer(Some(w));
+    }
+    fn on_start(&self) {
+        for _ in 0..self.0.queue.workers() {
+            let ext = self.clone();
+            tokio::spawn(async move { ext.run().await });
+        }
+        let ext = self.clone();
+        tokio::spawn(async move { ext.load_queue().await });
+    }
+    fn on_disconnected(&self, _reason: &str) {
+        self.0.messenger.set_writer(None);
+    }
+    async fn on_shutdown(&self, deadline: Instant) {
+        while self.0.queue.pending() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let pending = self.0.queue.pending();
+        if pending > 0 && self.0.cfg.queue_dir.is_some() {
+            self.warn(&format!("{pending} deliveries left in the queue dir"));
+        } else if pending > 0 {
+            self.warn(&format!("{pending} deliveries lost on shutdown"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::stub_http;
+
+    const MESSAGE: &str = "N0CALL-7>APRS,TCPIP*::WXBOT    :weather?{12";
+
+    fn webhook(url: String, reply: Reply) -> Webhook {
+        Webhook::new(&Config {
+            name: "test".into(),
+            url,
+            bearer_token: Some("t0ken".into()),
+            max_retries: 2,
+            initial_backoff_secs: 0,
+            reply,
+            reply_aliases: vec!["wxbot".into()],
+            filter: Filter {
+                addressee: vec!["WXBOT".into()],
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    async fn sent(rx: &mut mpsc::Receiver<Vec<u8>>) -> String {
+        let packet = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await;
+        String::from_utf8(packet.unwrap().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn delivers_with_retries_and_answers_once() {
+        let (url, mut requests) =
+            stub_http::serve(vec![(503, "busy"), (200, "sunny\nignored")], Duration::ZERO).await;
+        let ext = webhook(format!("{url}/aprs"), Reply::ResponseText);
+        let (tx, mut rx) = mpsc::channel(8);
+        ext.set_own_writer(tx);
+        ext.handle(MESSAGE).await;
+        //a retransmit while the first one is queued is not delivered again
+        ext.handle(MESSAGE).await;
+        assert_eq!(ext.0.queue.pending(), 1);
+        ext.on_start();
+        for _ in 0..2 {
+            let request = requests.recv().await.unwrap();
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path, "/aprs");
+            assert_eq!(request.headers["authorization"], "Bearer t0ken");
+            assert!(request.body.contains("weather?"));
+        }
+        assert_eq!(
+            sent(&mut rx).await,
+            "WXBOT>AP4GNT,TCPIP*::N0CALL-7 :ack12\n"
+        );
+        assert_eq!(
+            sent(&mut rx).await,
+            "WXBOT>AP4GNT,TCPIP*::N0CALL-7 :sunny\n"
+        );
+        //once answered a retransmit is only acked again
+        ext.handle(MESSAGE).await;
+        assert_eq!(
+            sent(&mut rx).await,
+            "WXBOT>AP4GNT,TCPIP*::N0CALL-7 :ack12\n"
+        );
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(requests.try_recv().is_err());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (url, mut requests) = stub_http::serve(vec![(404, "nope")], Duration::ZERO).await;
+        let ext = webhook(url, Reply::Ack);
+        let (tx, mut rx) = mpsc::channel(8);
+        ext.set_own_writer(tx);
+        ext.on_start();
+        ext.handle(MESSAGE).await;
+        requests.recv().await.unwrap();
+        ext.on_shutdown(Instant::now() + Duration::from_secs(5))
+            .await;
+        assert_eq!(ext.0.queue.pending(), 0);
+        assert!(requests.try_recv().is_err());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_the_oldest() {
+        let ext = Webhook::new(&Config {
+            name: "full".into(),
+            queue: QueueSettings {
+                capacity: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        for i in 0..5 {
+            ext.handle(&format!("N0CALL>APRS:>status {i}")).await;
+        }
+        assert_eq!(ext.0.queue.pending(), 2);
+        assert_eq!(ext.0.queue.dropped(), 3);
+        let (first, _processing) = ext.0.queue.pop().await;
+        assert!(first.body.contains("status 3"));
+    }
+}
@@ -25,5 +25,9 @@ pub fn parse() -> Flags {
     flags
 }
 pub fn flags() -> &'static Flags {
-    unsafe { FLAGS.as_ref().expect("flags are not initially parsed") }
+    unsafe {
+        (*std::ptr::addr_of!(FLAGS))
+            .as_ref()
+            .expect("flags are not initially parsed")
+    }
 }
@@ -4,6 +4,7 @@ mod error;
 mod extension_server;
 mod extensions;
 mod flags;
+mod shutdown;
 mod utils;
 
 pub use config::Config;
@@ -34,5 +35,7 @@ async fn main() {
         Default::default()
     };
     config.register_extensions();
-    aprs::start_server(config, ext_con_store).await;
+    let shutdown = shutdown::Shutdown::new();
+    shutdown::listen_for_signals(shutdown.clone());
+    aprs::start_server(config, ext_con_store, shutdown).await;
 }
@@ -0,0 +1,65 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// shutdown coordinator shared by the upstream loop and everything it drives
+/// once triggered the upstream loop stops reading, lets extensions drain and then disconnects
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+}
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, rx) = watch::channel(false);
+        Self {
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+    pub fn trigger(&self) {
+        self.tx.send(true).ok();
+    }
+    /// resolves once shutdown has been triggered
+    pub async fn wait(&self) {
+        let mut rx = self.rx.clone();
+        while !*rx.borrow() {
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// triggers the shutdown on SIGTERM or ctrl-c, a second signal exits immediately
+pub fn listen_for_signals(shutdown: Shutdown) {
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        eprintln!("shutdown requested, draining in-flight work");
+        shutdown.trigger();
+        wait_for_signal().await;
+        eprintln!("second shutdown signal received, exiting immediately");
+        std::process::exit(1);
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = term.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    tokio::signal::ctrl_c().await.ok();
+}
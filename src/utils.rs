@@ -3,3 +3,182 @@ use std::time::UNIX_EPOCH;
 pub fn now_unix() -> u64 {
     UNIX_EPOCH.elapsed().expect("Time went backwards").as_secs()
 }
+
+/// case insensitive glob match supporting `*` and `?` as used in aprs-is style callsign filters
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_uppercase().chars().collect::<Vec<_>>();
+    let text = text.to_uppercase().chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    p = bp + 1;
+                    t = bt + 1;
+                    backtrack = Some((bp, bt + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// great circle distance between two points in kilometers
+pub fn distance_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+/// replaces `{name}` placeholders using the lookup, unknown names are left as they are
+pub fn render_template(template: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('}').map(|end| (&after[..end], end)) {
+            Some((name, end)) if !name.contains('{') => {
+                match lookup(name) {
+                    Some(value) => out.push_str(&value),
+                    None => out.push_str(&rest[start..start + end + 2]),
+                }
+                rest = &after[end + 1..];
+            }
+            _ => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// formats a unix timestamp as `YYYY-MM-DD HH:MM:SS UTC`
+pub fn format_utc(ts: u64) -> String {
+    let (year, month, day) = civil_date(ts);
+    let secs = ts % 86400;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// formats a unix timestamp as the aprs `DDHHMMz` zulu timestamp
+pub fn aprs_timestamp(ts: u64) -> String {
+    let (_, _, day) = civil_date(ts);
+    let secs = ts % 86400;
+    format!("{day:02}{:02}{:02}z", secs / 3600, secs % 3600 / 60)
+}
+
+/// year, month and day of a unix timestamp in utc
+fn civil_date(ts: u64) -> (i64, i64, i64) {
+    //civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = (ts / 86400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// splits text into chunks of at most `max` characters at whitespace, overlong words are cut
+pub fn split_text(text: &str, max: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    for word in text.split_whitespace() {
+        let mut word = word.chars().collect::<Vec<_>>();
+        let needed = if chunk.is_empty() { 0 } else { 1 } + word.len();
+        if chunk.chars().count() + needed > max && !chunk.is_empty() {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        while word.len() > max {
+            chunks.push(word.drain(..max).collect());
+        }
+        if !chunk.is_empty() {
+            chunk.push(' ');
+        }
+        chunk.extend(word);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match("N0CALL", "n0call"));
+        assert!(glob_match("N0CALL*", "N0CALL-7"));
+        assert!(glob_match("N0CALL-?", "N0CALL-7"));
+        assert!(!glob_match("N0CALL-?", "N0CALL-10"));
+        assert!(glob_match("*@example.com", "bob@EXAMPLE.com"));
+        assert!(glob_match("a*b*c", "aXXbYYbc"));
+        assert!(!glob_match("a*b*c", "aXXbYYd"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("N0CALL", "N0CALL-1"));
+    }
+    #[test]
+    fn render_template_placeholders() {
+        let lookup = |name: &str| match name {
+            "from" => Some("N0CALL".to_string()),
+            "empty" => Some(String::new()),
+            _ => None,
+        };
+        assert_eq!(render_template("{from} says hi", lookup), "N0CALL says hi");
+        assert_eq!(render_template("[{empty}]", lookup), "[]");
+        assert_eq!(
+            render_template("{unknown} {from}", lookup),
+            "{unknown} N0CALL"
+        );
+        assert_eq!(render_template("{{from}}", lookup), "{N0CALL}");
+        assert_eq!(
+            render_template("open { and {from", lookup),
+            "open { and {from"
+        );
+    }
+    #[test]
+    fn format_utc_dates() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_utc(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_utc(1_700_000_000), "2023-11-14 22:13:20 UTC");
+    }
+    #[test]
+    fn split_text_chunks() {
+        assert_eq!(split_text("", 10), Vec::<String>::new());
+        assert_eq!(split_text("  hello   world ", 20), ["hello world"]);
+        assert_eq!(split_text("hello world", 5), ["hello", "world"]);
+        assert_eq!(split_text("a bb ccc dddd", 6), ["a bb", "ccc", "dddd"]);
+        assert_eq!(split_text("ab abcdefgh", 3), ["ab", "abc", "def", "gh"]);
+        assert_eq!(split_text("äöü äöü", 3), ["äöü", "äöü"]);
+    }
+    #[test]
+    fn aprs_timestamp_zulu() {
+        assert_eq!(aprs_timestamp(0), "010000z");
+        assert_eq!(aprs_timestamp(1_700_000_000), "142213z");
+    }
+}